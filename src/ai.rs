@@ -0,0 +1,204 @@
+use bevy::prelude::*;
+use bevy_rapier2d::prelude::Velocity;
+use leafwing_input_manager::prelude::*;
+
+use crate::{
+    arena::CurrentArena,
+    game::{Side, SpawnPoint, BALL_RADIUS},
+    Action,
+    Ball,
+    Paddle,
+};
+
+/// How good a computer-controlled paddle is.
+#[derive(Reflect, Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Difficulty {
+    Easy,
+    #[default]
+    Normal,
+    Hard,
+}
+
+impl Difficulty {
+    /// seconds between each time the ai looks at the ball
+    pub const fn reaction_delay(self) -> f32 {
+        match self {
+            Self::Easy => 0.35,
+            Self::Normal => 0.2,
+            Self::Hard => 0.08,
+        }
+    }
+
+    /// how far off (in pixels) the ai can be when guessing where the ball ends up
+    pub const fn aim_error(self) -> f32 {
+        match self {
+            Self::Easy => 70.0,
+            Self::Normal => 35.0,
+            Self::Hard => 8.0,
+        }
+    }
+
+    /// whether the ai will try rotating into the ball
+    pub const fn uses_rotation(self) -> bool {
+        !matches!(self, Self::Easy)
+    }
+}
+
+/// Drives a paddle's [`ActionState<Action>`] instead of an [`InputMap`], so
+/// `calc::paddle_sim` moves it exactly like a human-controlled one.
+#[derive(Component)]
+pub struct AiController {
+    pub difficulty: Difficulty,
+    reaction: Timer,
+    target: Option<Vec2>,
+    rotate_cooldown: Timer,
+    rng: u32,
+}
+
+impl AiController {
+    pub fn new(difficulty: Difficulty, seed: u32) -> Self {
+        Self {
+            difficulty,
+            reaction: Timer::from_seconds(difficulty.reaction_delay(), TimerMode::Repeating),
+            target: None,
+            rotate_cooldown: Timer::from_seconds(ROTATE_COOLDOWN, TimerMode::Once),
+            // xorshift gets stuck on 0
+            rng: seed.wrapping_mul(2_654_435_761) | 1,
+        }
+    }
+
+    /// xorshift32, mapped to -1..1
+    fn noise(&mut self) -> f32 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        (self.rng as f32 / u32::MAX as f32).mul_add(2.0, -1.0)
+    }
+}
+
+/// how close the ball has to be before the ai tries a rotation
+const ROTATE_RANGE: f32 = 130.0;
+const ROTATE_COOLDOWN: f32 = 0.6;
+/// how far ahead the ai looks when deciding whether to brake
+const STOPPING_TIME: f32 = 0.25;
+/// distance from the target that counts as "there"
+const DEADBAND: f32 = 8.0;
+
+/// Where the ball will cross `target_x`, bouncing off the top and bottom
+/// walls (`arena_half_height` above and below the middle) on the way. `None`
+/// if it's moving away from `target_x`.
+pub fn predict_intercept(
+    position: Vec2,
+    velocity: Vec2,
+    target_x: f32,
+    arena_half_height: f32,
+) -> Option<f32> {
+    let time = (target_x - position.x) / velocity.x;
+    if !time.is_finite() || time < 0.0 {
+        return None;
+    }
+    let half_height = arena_half_height - BALL_RADIUS;
+    let span = 2.0 * half_height;
+    // unfold the bounces: the ball travels back and forth over `span`
+    let mut y = velocity.y.mul_add(time, position.y + half_height);
+    y = y.rem_euclid(2.0 * span);
+    if y > span {
+        y = 2.0 * span - y;
+    }
+    Some(y - half_height)
+}
+
+/// Which way to push to reach `diff` away, given the current velocity.
+fn steer(diff: f32, vel: f32) -> Option<bool> {
+    let error = vel.mul_add(-STOPPING_TIME, diff);
+    if error > DEADBAND {
+        Some(true)
+    } else if error < -DEADBAND {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+fn press_if(action_state: &mut ActionState<Action>, action: Action, pressed: bool) {
+    if pressed {
+        action_state.press(action);
+    } else {
+        action_state.release(action);
+    }
+}
+
+#[allow(clippy::type_complexity)]
+pub fn ai_control(
+    time: Res<Time>,
+    arena: Res<CurrentArena>,
+    ball_query: Query<(&Transform, &Velocity), (With<Ball>, Without<Paddle>)>,
+    mut ai_query: Query<
+        (
+            &mut AiController,
+            &mut ActionState<Action>,
+            &Transform,
+            &Velocity,
+            &SpawnPoint,
+            &Side,
+        ),
+        With<Paddle>,
+    >,
+) {
+    let Ok((ball_transform, ball_vel)) = ball_query.get_single() else {
+        return;
+    };
+    let ball_pos = ball_transform.translation.truncate();
+
+    for (mut ai, mut action_state, transform, vel, spawn, side) in &mut ai_query {
+        let position = transform.translation.truncate();
+        let difficulty = ai.difficulty;
+
+        if ai.reaction.tick(time.delta()).just_finished() {
+            let approaching = ball_vel.linvel.x * side.sign() > 0.0;
+            ai.target = if approaching {
+                predict_intercept(
+                    ball_pos,
+                    ball_vel.linvel,
+                    spawn.0.x,
+                    arena.arena.half_size.y,
+                )
+                .map(|y| {
+                    let error = ai.noise() * difficulty.aim_error();
+                    Vec2::new(spawn.0.x, y + error)
+                })
+            } else {
+                // drift back home while the ball is on the other side
+                Some(spawn.0)
+            };
+        }
+
+        let target = ai.target.unwrap_or(spawn.0);
+        let diff = target - position;
+        let horizontal = steer(diff.x, vel.linvel.x);
+        let vertical = steer(diff.y, vel.linvel.y);
+        press_if(&mut action_state, Action::Right, horizontal == Some(true));
+        press_if(&mut action_state, Action::Left, horizontal == Some(false));
+        press_if(&mut action_state, Action::Up, vertical == Some(true));
+        press_if(&mut action_state, Action::Down, vertical == Some(false));
+
+        // tap a rotation as the ball comes in, swinging the near end into it
+        action_state.release(Action::RotateClockwise);
+        action_state.release(Action::RotateAntiClockwise);
+        ai.rotate_cooldown.tick(time.delta());
+        let incoming = ball_vel.linvel.x * side.sign() > 0.0;
+        if difficulty.uses_rotation()
+            && incoming
+            && ai.rotate_cooldown.finished()
+            && ball_pos.distance(position) < ROTATE_RANGE
+        {
+            let clockwise = (ball_pos.y > position.y) == (*side == Side::Left);
+            action_state.press(if clockwise {
+                Action::RotateClockwise
+            } else {
+                Action::RotateAntiClockwise
+            });
+            ai.rotate_cooldown.reset();
+        }
+    }
+}
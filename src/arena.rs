@@ -0,0 +1,332 @@
+use std::any::TypeId;
+
+use bevy::{
+    asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext, LoadedFolder},
+    prelude::*,
+    reflect::TypePath,
+    utils::BoxedFuture,
+};
+use bevy_rapier2d::prelude::*;
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::game::{GameEntity, Goal, Side};
+
+/// A level, loaded from an `assets/arenas/*.arena.ron` file.
+#[derive(Asset, TypePath, Deserialize, Clone, Debug)]
+pub struct Arena {
+    /// half the width and height of the playable area, used for aiming
+    pub half_size: Vec2,
+    pub walls: Vec<WallDef>,
+    /// where each player starts, in roster order
+    pub spawns: Vec<SpawnDef>,
+    #[serde(default)]
+    pub ball_spawn: Vec2,
+    #[serde(default)]
+    pub obstacles: Vec<ObstacleDef>,
+    #[serde(default)]
+    pub background: Background,
+}
+
+/// The ball's collision group. Goal barriers leave it out, so they only stop
+/// paddles.
+pub const BALL_GROUP: Group = Group::GROUP_1;
+/// how thick the barrier behind each goal is
+const GOAL_BARRIER_THICKNESS: f32 = 10.0;
+
+/// An edge of the arena. Walls with a `goal` are sensors instead of solid,
+/// with a barrier just behind them that only paddles bump into.
+#[derive(Deserialize, Clone, Debug)]
+pub struct WallDef {
+    pub position: Vec2,
+    pub half_extents: Vec2,
+    /// the side that has to defend this wall
+    #[serde(default)]
+    pub goal: Option<Side>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+pub struct SpawnDef {
+    pub position: Vec2,
+    pub side: Side,
+}
+
+/// Something solid in the way of the ball.
+#[derive(Deserialize, Clone, Debug)]
+pub struct ObstacleDef {
+    pub position: Vec2,
+    pub shape: ObstacleShape,
+    /// in degrees
+    #[serde(default)]
+    pub rotation: f32,
+    #[serde(default = "default_obstacle_colour")]
+    pub colour: Color,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+pub enum ObstacleShape {
+    Box(Vec2),
+    Circle(f32),
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Background {
+    pub colour: Color,
+    /// path to an image in the assets folder, stretched over the arena
+    #[serde(default)]
+    pub image: Option<String>,
+}
+
+impl Default for Background {
+    fn default() -> Self {
+        Self {
+            colour: Color::rgb(0.1, 0.1, 0.12),
+            image: None,
+        }
+    }
+}
+
+const fn default_obstacle_colour() -> Color {
+    Color::rgb(0.6, 0.6, 0.6)
+}
+
+/// The built-in arena, used if the requested one hasn't loaded.
+impl Default for Arena {
+    fn default() -> Self {
+        let wall = |position, half_extents, goal| WallDef {
+            position,
+            half_extents,
+            goal,
+        };
+        Self {
+            half_size: Vec2::new(500.0, 250.0),
+            walls: vec![
+                wall(
+                    Vec2::new(-500.0, 0.0),
+                    Vec2::new(0.0, 1000.0),
+                    Some(Side::Left),
+                ),
+                wall(
+                    Vec2::new(500.0, 0.0),
+                    Vec2::new(0.0, 1000.0),
+                    Some(Side::Right),
+                ),
+                wall(Vec2::new(0.0, -250.0), Vec2::new(1000.0, 0.0), None),
+                wall(Vec2::new(0.0, 250.0), Vec2::new(1000.0, 0.0), None),
+            ],
+            spawns: vec![],
+            ball_spawn: Vec2::ZERO,
+            obstacles: vec![],
+            background: Background::default(),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ArenaLoaderError {
+    #[error("could not read arena file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("could not parse arena file: {0}")]
+    Ron(#[from] ron::error::SpannedError),
+}
+
+#[derive(Default)]
+pub struct ArenaLoader;
+
+impl AssetLoader for ArenaLoader {
+    type Asset = Arena;
+    type Error = ArenaLoaderError;
+    type Settings = ();
+
+    fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader,
+        _settings: &'a (),
+        _load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<Arena, ArenaLoaderError>> {
+        Box::pin(async move {
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes).await?;
+            Ok(ron::de::from_bytes(&bytes)?)
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["arena.ron"]
+    }
+}
+
+/// Every arena in `assets/arenas`.
+#[derive(Resource)]
+pub struct Arenas(pub Handle<LoadedFolder>);
+
+impl Arenas {
+    /// The names (file names without `.arena.ron`) of every loaded arena.
+    pub fn names(&self, folders: &Assets<LoadedFolder>) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .handles(folders)
+            .filter_map(|handle| arena_name(&handle))
+            .collect();
+        names.sort();
+        names
+    }
+
+    pub fn get(&self, folders: &Assets<LoadedFolder>, name: &str) -> Option<Handle<Arena>> {
+        self.handles(folders)
+            .find(|handle| arena_name(handle).as_deref() == Some(name))
+    }
+
+    fn handles<'a>(
+        &self,
+        folders: &'a Assets<LoadedFolder>,
+    ) -> impl Iterator<Item = Handle<Arena>> + 'a {
+        folders
+            .get(&self.0)
+            .into_iter()
+            .flat_map(|folder| folder.handles.iter())
+            // anything else in the folder isn't an arena
+            .filter(|handle| handle.type_id() == TypeId::of::<Arena>())
+            .map(|handle| handle.clone().typed::<Arena>())
+    }
+}
+
+fn arena_name(handle: &Handle<Arena>) -> Option<String> {
+    let name = handle.path()?.path().file_name()?.to_str()?;
+    Some(name.strip_suffix(".arena.ron").unwrap_or(name).to_string())
+}
+
+/// The arena the current match is being played in.
+#[derive(Resource)]
+pub struct CurrentArena {
+    pub handle: Option<Handle<Arena>>,
+    pub arena: Arena,
+}
+
+/// Walls, obstacles and background: the parts of a match that get rebuilt
+/// when the arena file changes.
+#[derive(Component)]
+pub struct ArenaEntity;
+
+pub fn load_arenas(mut commands: Commands, server: Res<AssetServer>) {
+    commands.insert_resource(Arenas(server.load_folder("arenas")));
+}
+
+pub fn spawn_arena(commands: &mut Commands, arena: &Arena, server: &AssetServer) {
+    for wall in &arena.walls {
+        let mut entity = commands.spawn(TransformBundle::from(Transform::from_translation(
+            wall.position.extend(0.0),
+        )));
+        entity
+            .insert(RigidBody::Fixed)
+            .insert(Collider::cuboid(wall.half_extents.x, wall.half_extents.y))
+            .insert(Restitution::coefficient(0.0))
+            .insert(ArenaEntity)
+            .insert(GameEntity);
+        if let Some(side) = wall.goal {
+            entity
+                .insert(Sensor)
+                .insert(Goal(side))
+                .insert(ActiveEvents::COLLISION_EVENTS);
+            spawn_goal_barrier(commands, wall);
+        }
+    }
+
+    for obstacle in &arena.obstacles {
+        let (collider, size) = match obstacle.shape {
+            ObstacleShape::Box(half_extents) => (
+                Collider::cuboid(half_extents.x, half_extents.y),
+                half_extents * 2.0,
+            ),
+            ObstacleShape::Circle(radius) => (Collider::ball(radius), Vec2::splat(radius * 2.0)),
+        };
+        commands.spawn((
+            SpriteBundle {
+                sprite: Sprite {
+                    color: obstacle.colour,
+                    custom_size: Some(size),
+                    ..default()
+                },
+                transform: Transform::from_translation(obstacle.position.extend(0.0))
+                    .with_rotation(Quat::from_rotation_z(obstacle.rotation.to_radians())),
+                ..default()
+            },
+            RigidBody::Fixed,
+            collider,
+            Restitution::coefficient(1.0),
+            ArenaEntity,
+            GameEntity,
+        ));
+    }
+
+    commands.spawn((
+        SpriteBundle {
+            sprite: Sprite {
+                color: arena.background.colour,
+                custom_size: Some(arena.half_size * 2.0),
+                ..default()
+            },
+            texture: arena
+                .background
+                .image
+                .as_ref()
+                .map_or_else(Handle::default, |path| server.load(path)),
+            // behind everything else
+            transform: Transform::from_xyz(0.0, 0.0, -10.0),
+            ..default()
+        },
+        ArenaEntity,
+        GameEntity,
+    ));
+}
+
+/// Keeps paddles from being driven through a goal and out of the arena,
+/// while the ball still goes through to the goal sensor.
+fn spawn_goal_barrier(commands: &mut Commands, wall: &WallDef) {
+    // the goal's thin side faces the middle of the arena
+    let outwards = if wall.half_extents.x <= wall.half_extents.y {
+        Vec2::new(wall.position.x.signum(), 0.0)
+    } else {
+        Vec2::new(0.0, wall.position.y.signum())
+    };
+    let half_thickness = GOAL_BARRIER_THICKNESS / 2.0;
+    let half_extents = wall.half_extents + outwards.abs() * half_thickness;
+    let position = wall.position + outwards * (wall.half_extents + half_thickness);
+    commands.spawn((
+        TransformBundle::from(Transform::from_translation(position.extend(0.0))),
+        RigidBody::Fixed,
+        Collider::cuboid(half_extents.x, half_extents.y),
+        CollisionGroups::new(Group::ALL, Group::ALL - BALL_GROUP),
+        ArenaEntity,
+        GameEntity,
+    ));
+}
+
+/// Rebuilds the arena whenever its file changes on disk. Only does anything
+/// with the `hot_reload` feature on.
+pub fn reload_arena(
+    mut commands: Commands,
+    mut events: EventReader<AssetEvent<Arena>>,
+    arenas: Res<Assets<Arena>>,
+    current: Option<ResMut<CurrentArena>>,
+    server: Res<AssetServer>,
+    query: Query<Entity, With<ArenaEntity>>,
+) {
+    let Some(mut current) = current else {
+        return;
+    };
+    let Some(handle) = current.handle.clone() else {
+        return;
+    };
+    let modified = events.read().any(|event| {
+        event.is_modified(handle.id()) || event.is_loaded_with_dependencies(handle.id())
+    });
+    let Some(arena) = arenas.get(&handle).filter(|_| modified) else {
+        return;
+    };
+    info!("reloading arena {:?}", handle.path());
+    for entity in &query {
+        commands.entity(entity).despawn_recursive();
+    }
+    current.arena = arena.clone();
+    spawn_arena(&mut commands, arena, &server);
+}
@@ -0,0 +1,35 @@
+//! Compares two checksum logs written by `ChecksumPlugin` and prints where
+//! they first disagree.
+//!
+//! usage: checksum_diff <a.log> <b.log>
+
+use std::{env, fs, process::ExitCode};
+
+use ppan::checksum::compare_logs;
+
+fn main() -> ExitCode {
+    let paths: Vec<String> = env::args().skip(1).collect();
+    let [a, b] = paths.as_slice() else {
+        eprintln!("usage: checksum_diff <a.log> <b.log>");
+        return ExitCode::from(2);
+    };
+    let read =
+        |path: &str| fs::read_to_string(path).map_err(|e| eprintln!("couldn't read {path}: {e}"));
+    let (Ok(a_log), Ok(b_log)) = (read(a), read(b)) else {
+        return ExitCode::from(2);
+    };
+    match compare_logs(&a_log, &b_log) {
+        Ok(None) => {
+            println!("logs match");
+            ExitCode::SUCCESS
+        }
+        Ok(Some(divergence)) => {
+            println!("{divergence}");
+            ExitCode::FAILURE
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::from(2)
+        }
+    }
+}
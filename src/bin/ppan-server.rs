@@ -0,0 +1,41 @@
+//! Hosts online matches, without a window. Clients join a room with
+//! `ppan --connect <server address> <room>`, and a room's match starts as
+//! soon as a second player joins it.
+//!
+//! usage: ppan-server [--port <port>] [--tick-rate <ticks a second>]
+
+use std::{env, net::Ipv4Addr, process::ExitCode};
+
+use bevy::prelude::*;
+use ppan::{game::TICK_RATE, server::Server};
+
+const DEFAULT_PORT: u16 = 7777;
+
+fn main() -> ExitCode {
+    let mut port = DEFAULT_PORT;
+    let mut tick_rate = TICK_RATE as u16;
+    let mut args = env::args().skip(1);
+    while let Some(flag) = args.next() {
+        let value = args.next().and_then(|value| value.parse().ok());
+        match (flag.as_str(), value) {
+            ("--port", Some(value)) => port = value,
+            ("--tick-rate", Some(value)) => tick_rate = value,
+            _ => {
+                eprintln!("usage: ppan-server [--port <port>] [--tick-rate <ticks a second>]");
+                return ExitCode::from(2);
+            }
+        }
+    }
+
+    tracing_subscriber::fmt().init();
+
+    let mut server = match Server::bind((Ipv4Addr::UNSPECIFIED, port), tick_rate) {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("couldn't start the server: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    info!("listening on port {port} at {tick_rate} ticks a second");
+    server.run()
+}
@@ -1,97 +1,234 @@
-use std::f32::consts::PI;
+use std::collections::VecDeque;
 
 use bevy::prelude::*;
 use bevy_rapier2d::prelude::Velocity;
 use leafwing_input_manager::prelude::*;
 
-use crate::{Acceleration, Action, NextStop, RotAcceleration, Rotating, RotatingM};
+use crate::{
+    Acceleration,
+    Action,
+    NextStop,
+    QueuedRotation,
+    RotAcceleration,
+    Rotating,
+    RotatingM,
+    RotationQueue,
+    RotationStep,
+    RotationVelocity,
+};
 
 pub const ACCELERATION: Acceleration = Acceleration(60.0);
 pub const ROT_ACCELERATION: RotAcceleration = RotAcceleration(0.0003);
 
-pub fn paddle_sim(
-    mut transform: Mut<'_, Transform>,
-    mut rotating: Mut<'_, Rotating>,
-    mut next_stop: Mut<'_, NextStop>,
-    action_state: &ActionState<Action>,
-    mut vel: Mut<'_, Velocity>,
-    acceleration: &Acceleration,
-    rot_acceleration: &RotAcceleration,
-) {
-    // -- Reading actions
-
-    // convert to degrees
-    let mut rotation_deg = 180.0 - transform.rotation.to_euler(EulerRot::YXZ).2.to_degrees();
+/// how long a rotate press waits for its turn before it's dropped, in seconds
+const ROTATION_BUFFER: f32 = 0.25;
+/// presses beyond this are ignored rather than queued
+const MAX_QUEUED_ROTATIONS: usize = 2;
+/// furthest a paddle can be told to turn ahead of where it is, in steps
+const MAX_STEPS_AHEAD: f32 = 2.0;
+/// free rotation tops out at the fastest a turn of this many degrees gets
+const FREE_ROTATION_TURN: f32 = 90.0;
+/// how many times [`RotAcceleration`] free rotation speeds up by each frame
+const FREE_ROTATION_SPIN_UP: f32 = 60.0;
 
-    // if the diff between rotation and the next stop is less than .1, set the rotation to the next stop
-    if (rotating.0 == RotatingM::AntiClockwise || rotating.0 == RotatingM::Clockwise)
-        && (rotation_deg - next_stop.0).abs() < 5.0
-    {
-        rotating.0 = RotatingM::Neither;
-    }
+/// how far a stick can drift from the middle and still read as nothing
+pub const STICK_DEADZONE: f32 = 0.15;
+/// above 1, small stick movements are finer and the edge is still full speed
+const STICK_RESPONSE: f32 = 1.6;
 
-    if rotating.0 == RotatingM::Clockwise && rotating.0 == RotatingM::AntiClockwise {
-        // only keep cw
-        rotating.0 = RotatingM::Clockwise;
+/// Rescales a stick position so movement starts from 0 at the edge of the
+/// deadzone, then bends it by [`STICK_RESPONSE`].
+pub fn stick_response(stick: Vec2) -> Vec2 {
+    let magnitude = stick.length().min(1.0);
+    if magnitude <= STICK_DEADZONE {
+        return Vec2::ZERO;
     }
+    let scaled = ((magnitude - STICK_DEADZONE) / (1.0 - STICK_DEADZONE)).powf(STICK_RESPONSE);
+    stick.normalize() * scaled
+}
 
-    if action_state.pressed(Action::RotateAntiClockwise) {
-        rotating.0 = RotatingM::AntiClockwise;
-        // get next 90 degree rotation to the left
-        next_stop.0 = 90.0f32.mul_add((rotation_deg / 90.0).round(), -90.0);
-        while next_stop.0 < 0.0 {
-            next_stop.0 += 360.0;
+/// Adds this frame's rotate presses to the queue. Presses are edges, so this
+/// runs once per frame rather than once per tick.
+pub fn queue_rotations(action_state: &ActionState<Action>, queue: &mut RotationQueue) {
+    for (action, clockwise) in [
+        (Action::RotateAntiClockwise, false),
+        (Action::RotateClockwise, true),
+    ] {
+        if action_state.just_pressed(action) {
+            queue_rotation(queue, clockwise);
         }
-        next_stop.0 %= 360.0;
     }
+}
 
-    if action_state.pressed(Action::RotateClockwise) {
-        rotating.0 = RotatingM::Clockwise;
-        // get next 90 degree rotation to the right
-        next_stop.0 = 90.0f32.mul_add((rotation_deg / 90.0).round(), 90.0);
-        while next_stop.0 < 0.0 {
-            next_stop.0 += 360.0;
-        }
-        next_stop.0 %= 360.0;
+/// Queues one rotate press, unless too many are waiting already.
+pub fn queue_rotation(queue: &mut RotationQueue, clockwise: bool) {
+    if queue.0.len() < MAX_QUEUED_ROTATIONS {
+        queue.0.push_back(QueuedRotation {
+            clockwise,
+            age: 0.0,
+        });
     }
-    if (rotation_deg - next_stop.0).abs() < 0.1 {
-        transform.rotation = Quat::from_rotation_z(next_stop.0.to_radians());
-        rotation_deg = next_stop.0;
-        rotating.0 = RotatingM::Neither;
+}
+
+/// Everything about a paddle's movement that changes from tick to tick,
+/// without any ECS types, so it can be stepped from anywhere.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct PaddleState {
+    /// in degrees, 0 to 360, increasing clockwise
+    pub angle: f32,
+    /// the angle the paddle is turning towards
+    pub next_stop: f32,
+    pub rotating: RotatingM,
+    /// how far the paddle turned last tick, in radians. Positive is anticlockwise.
+    pub rotation_velocity: f32,
+    pub velocity: Vec2,
+    /// rotate presses waiting for their turn
+    pub queue: VecDeque<QueuedRotation>,
+}
+
+impl PaddleState {
+    /// The [`PaddleState::angle`] of a paddle turned this way.
+    pub fn angle_of(rotation: Quat) -> f32 {
+        (180.0 - rotation.to_euler(EulerRot::YXZ).2.to_degrees()).rem_euclid(360.0)
     }
 
-    if action_state.pressed(Action::Right) {
-        vel.linvel.x += acceleration.0;
+    /// The rotation that draws a paddle at `angle`.
+    pub fn rotation(angle: f32) -> Quat {
+        Quat::from_rotation_z((180.0 - angle).to_radians())
     }
-    if action_state.pressed(Action::Left) {
-        vel.linvel.x -= acceleration.0;
+}
+
+/// What a paddle's player is holding down this tick.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct PaddleInput {
+    /// from the direction buttons, -1, 0 or 1 on each axis
+    pub direction: Vec2,
+    /// raw analog stick position, before the deadzone
+    pub stick: Vec2,
+    pub clockwise: bool,
+    pub anticlockwise: bool,
+}
+
+impl PaddleInput {
+    pub fn from_action_state(action_state: &ActionState<Action>) -> Self {
+        let axis = |negative, positive| {
+            f32::from(u8::from(action_state.pressed(positive)))
+                - f32::from(u8::from(action_state.pressed(negative)))
+        };
+        Self {
+            direction: Vec2::new(
+                axis(Action::Left, Action::Right),
+                axis(Action::Down, Action::Up),
+            ),
+            stick: action_state
+                .axis_pair(Action::Move)
+                .map_or(Vec2::ZERO, |stick| stick.xy()),
+            clockwise: action_state.pressed(Action::RotateClockwise),
+            anticlockwise: action_state.pressed(Action::RotateAntiClockwise),
+        }
     }
-    if action_state.pressed(Action::Down) {
-        vel.linvel.y -= acceleration.0;
+}
+
+/// How a paddle handles, which stays the same all match.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PaddleTuning {
+    pub acceleration: f32,
+    pub rot_acceleration: f32,
+    pub rotation_step: RotationStep,
+}
+
+impl Default for PaddleTuning {
+    fn default() -> Self {
+        Self {
+            acceleration: ACCELERATION.0,
+            rot_acceleration: ROT_ACCELERATION.0,
+            rotation_step: RotationStep::default(),
+        }
     }
-    if action_state.pressed(Action::Up) {
-        vel.linvel.y += acceleration.0;
+}
+
+/// Moves a paddle on by one tick of `delta` seconds.
+pub fn step_paddle(
+    mut state: PaddleState,
+    input: &PaddleInput,
+    tuning: &PaddleTuning,
+    delta: f32,
+) -> PaddleState {
+    // -- Reading actions
+
+    state.velocity += input.direction * tuning.acceleration;
+    // the stick pushes as hard as it's tilted
+    state.velocity += stick_response(input.stick) * tuning.acceleration;
+
+    let step = match tuning.rotation_step {
+        RotationStep::Step(step) => step,
+        RotationStep::Free => {
+            free_rotation(input, &mut state, tuning.rot_acceleration);
+            turn(&mut state);
+            return state;
+        }
+    };
+
+    // close enough to the stop to stop trying to turn towards it
+    if state.rotating != RotatingM::Neither && offset_to_stop(&state).abs() < 5.0 {
+        state.rotating = RotatingM::Neither;
     }
 
-    // -- Calculation
+    for press in &mut state.queue {
+        press.age += delta;
+    }
+    state.queue.retain(|press| press.age <= ROTATION_BUFFER);
 
-    if (rotation_deg - next_stop.0).abs() > 2.0 {
-        let displacement_clockwise = if rotation_deg < next_stop.0 {
-            next_stop.0 - rotation_deg
+    while let Some(press) = state.queue.front() {
+        let direction = if press.clockwise {
+            RotatingM::Clockwise
         } else {
-            next_stop.0 + (360.0 - rotation_deg)
+            RotatingM::AntiClockwise
         };
-        let displacement_counterclockwise = if rotation_deg > next_stop.0 {
-            next_stop.0 - rotation_deg
+        // carry on from the current target if already turning that way,
+        // otherwise start from the nearest stop
+        let from = if state.rotating == direction {
+            state.next_stop
         } else {
-            next_stop.0 - rotation_deg - 360.0
+            step * (state.angle / step).round()
         };
-        println!("{rotation_deg}d {displacement_clockwise} {displacement_counterclockwise}");
+        let target = if press.clockwise {
+            from + step
+        } else {
+            from - step
+        }
+        .rem_euclid(360.0);
+        let ahead = if press.clockwise {
+            target - state.angle
+        } else {
+            state.angle - target
+        }
+        .rem_euclid(360.0);
+        if ahead > MAX_STEPS_AHEAD.mul_add(step, 5.0) {
+            // wait until the current turn has got further
+            break;
+        }
+        state.queue.pop_front();
+        state.rotating = direction;
+        state.next_stop = target;
+    }
+
+    if offset_to_stop(&state).abs() < 0.1 {
+        state.angle = state.next_stop;
+        state.rotating = RotatingM::Neither;
+    }
+
+    // -- Calculation
 
-        let displacement = match rotating.0 {
+    let offset = offset_to_stop(&state);
+    if offset.abs() > 2.0 {
+        let displacement_clockwise = (state.next_stop - state.angle).rem_euclid(360.0);
+        let displacement_counterclockwise = displacement_clockwise - 360.0;
+
+        let displacement = match state.rotating {
             RotatingM::Neither => {
                 // go for the closest one
-                if (displacement_clockwise).abs() < (displacement_counterclockwise).abs() {
+                if displacement_clockwise.abs() < displacement_counterclockwise.abs() {
                     displacement_clockwise
                 } else {
                     displacement_counterclockwise
@@ -101,18 +238,353 @@ pub fn paddle_sim(
             RotatingM::AntiClockwise => displacement_counterclockwise,
         };
 
-        let mut rotation_velocity = (2.0 * rot_acceleration.0 * displacement.abs()).sqrt();
+        let mut speed = (2.0 * tuning.rot_acceleration * displacement.abs()).sqrt();
         if displacement < 0.0 {
-            rotation_velocity *= -1.0;
+            speed *= -1.0;
         }
-        println!(
-            "nxs {} disp {} vel {} d {:?}",
-            next_stop.0, displacement, rotation_velocity, rotating.0
-        );
 
-        transform.rotate_z(-rotation_velocity);
+        state.rotation_velocity = -speed;
     } else {
         // just get closer to the next stop
-        transform.rotate_z(-(next_stop.0 - rotation_deg).to_radians() / 5.0);
+        state.rotation_velocity = -offset.to_radians() / 5.0;
+    }
+    turn(&mut state);
+    state
+}
+
+/// The shortest turn from the paddle's angle to its next stop, in degrees,
+/// positive clockwise. Goes the short way across 0°.
+fn offset_to_stop(state: &PaddleState) -> f32 {
+    (state.next_stop - state.angle + 180.0).rem_euclid(360.0) - 180.0
+}
+
+/// Applies a tick of [`PaddleState::rotation_velocity`] to the angle.
+fn turn(state: &mut PaddleState) {
+    state.angle = (state.angle - state.rotation_velocity.to_degrees()).rem_euclid(360.0);
+}
+
+/// Runs [`step_paddle`] on a paddle's components.
+#[allow(clippy::too_many_arguments)]
+pub fn paddle_sim(
+    delta: f32,
+    mut transform: Mut<'_, Transform>,
+    mut rotating: Mut<'_, Rotating>,
+    mut queue: Mut<'_, RotationQueue>,
+    mut next_stop: Mut<'_, NextStop>,
+    mut rotation_velocity: Mut<'_, RotationVelocity>,
+    action_state: &ActionState<Action>,
+    mut vel: Mut<'_, Velocity>,
+    acceleration: &Acceleration,
+    rot_acceleration: &RotAcceleration,
+    rotation_step: &RotationStep,
+) {
+    let state = PaddleState {
+        angle: PaddleState::angle_of(transform.rotation),
+        next_stop: next_stop.0,
+        rotating: rotating.0,
+        rotation_velocity: rotation_velocity.0,
+        velocity: vel.linvel,
+        queue: std::mem::take(&mut queue.0),
+    };
+    let tuning = PaddleTuning {
+        acceleration: acceleration.0,
+        rot_acceleration: rot_acceleration.0,
+        rotation_step: *rotation_step,
+    };
+    let state = step_paddle(
+        state,
+        &PaddleInput::from_action_state(action_state),
+        &tuning,
+        delta,
+    );
+
+    transform.rotation = PaddleState::rotation(state.angle);
+    next_stop.0 = state.next_stop;
+    rotating.0 = state.rotating;
+    rotation_velocity.0 = state.rotation_velocity;
+    vel.linvel = state.velocity;
+    queue.0 = state.queue;
+}
+
+/// Spins up while a rotate action is held, and slows back down when it's let
+/// go, without stopping at any particular angle.
+fn free_rotation(input: &PaddleInput, state: &mut PaddleState, rot_acceleration: f32) {
+    let top_speed = (2.0 * rot_acceleration * FREE_ROTATION_TURN).sqrt();
+    let (direction, target) = match (input.clockwise, input.anticlockwise) {
+        (true, false) => (RotatingM::Clockwise, -top_speed),
+        (false, true) => (RotatingM::AntiClockwise, top_speed),
+        _ => (RotatingM::Neither, 0.0),
+    };
+    let change = rot_acceleration * FREE_ROTATION_SPIN_UP;
+    state.rotation_velocity += (target - state.rotation_velocity).clamp(-change, change);
+    state.rotating = direction;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DELTA: f32 = 1.0 / 60.0;
+
+    fn at(angle: f32) -> PaddleState {
+        PaddleState {
+            angle,
+            next_stop: angle,
+            ..default()
+        }
+    }
+
+    fn press(state: &mut PaddleState, clockwise: bool) {
+        state.queue.push_back(QueuedRotation {
+            clockwise,
+            age: 0.0,
+        });
+    }
+
+    /// Steps with no input until the paddle stops turning, or gives up.
+    fn settle(mut state: PaddleState) -> PaddleState {
+        for _ in 0..600 {
+            state = step_paddle(
+                state,
+                &PaddleInput::default(),
+                &PaddleTuning::default(),
+                DELTA,
+            );
+            if state.rotating == RotatingM::Neither && state.angle == state.next_stop {
+                break;
+            }
+        }
+        state
+    }
+
+    #[test]
+    fn angle_round_trips_through_rotation() {
+        for angle in [0.0, 45.0, 90.0, 180.0, 270.0, 359.0] {
+            let back = PaddleState::angle_of(PaddleState::rotation(angle));
+            assert!((back - angle).abs() < 1e-3, "{angle} -> {back}");
+        }
+        // a freshly spawned paddle faces 0°
+        assert!(PaddleState::angle_of(Quat::from_rotation_z(std::f32::consts::PI)).abs() < 1e-3);
+    }
+
+    #[test]
+    fn clockwise_press_turns_one_step() {
+        let mut state = at(0.0);
+        press(&mut state, true);
+        let state = step_paddle(
+            state,
+            &PaddleInput::default(),
+            &PaddleTuning::default(),
+            DELTA,
+        );
+        assert_eq!(state.next_stop, 90.0);
+        assert_eq!(state.rotating, RotatingM::Clockwise);
+        assert!(state.rotation_velocity < 0.0, "clockwise is negative");
+        assert!(state.angle > 0.0 && state.angle < 90.0);
+        assert_eq!(settle(state).angle, 90.0);
+    }
+
+    #[test]
+    fn anticlockwise_from_zero_wraps_to_270() {
+        let mut state = at(0.0);
+        press(&mut state, false);
+        let state = step_paddle(
+            state,
+            &PaddleInput::default(),
+            &PaddleTuning::default(),
+            DELTA,
+        );
+        assert_eq!(state.next_stop, 270.0);
+        assert!(
+            state.angle > 270.0 && state.angle < 360.0,
+            "{}",
+            state.angle
+        );
+        assert_eq!(settle(state).angle, 270.0);
+    }
+
+    #[test]
+    fn clockwise_from_270_wraps_to_zero() {
+        let mut state = at(270.0);
+        press(&mut state, true);
+        let state = settle(state);
+        assert_eq!(state.next_stop, 0.0);
+        assert_eq!(state.angle, 0.0);
+    }
+
+    #[test]
+    fn crossing_zero_doesnt_turn_the_long_way() {
+        // just short of the stop on the far side of 0°
+        let state = PaddleState {
+            angle: 359.0,
+            next_stop: 0.0,
+            ..default()
+        };
+        let state = step_paddle(
+            state,
+            &PaddleInput::default(),
+            &PaddleTuning::default(),
+            DELTA,
+        );
+        assert!(state.rotation_velocity < 0.0, "turns clockwise over 0°");
+        assert!(state.angle > 359.0 || state.angle < 1.0, "{}", state.angle);
+    }
+
+    #[test]
+    fn snaps_onto_a_close_stop() {
+        for (angle, stop) in [(89.95, 90.0), (90.05, 90.0), (359.95, 0.0), (0.05, 0.0)] {
+            let state = PaddleState {
+                angle,
+                next_stop: stop,
+                rotating: RotatingM::Clockwise,
+                ..default()
+            };
+            let state = step_paddle(
+                state,
+                &PaddleInput::default(),
+                &PaddleTuning::default(),
+                DELTA,
+            );
+            assert_eq!(state.angle, stop, "from {angle}");
+            assert_eq!(state.rotating, RotatingM::Neither);
+        }
+    }
+
+    #[test]
+    fn idle_paddle_takes_the_short_way_back() {
+        for (angle, clockwise) in [(30.0, false), (330.0, true), (60.0, false), (300.0, true)] {
+            let state = PaddleState {
+                angle,
+                next_stop: 0.0,
+                ..default()
+            };
+            let state = step_paddle(
+                state,
+                &PaddleInput::default(),
+                &PaddleTuning::default(),
+                DELTA,
+            );
+            assert_eq!(
+                state.rotation_velocity < 0.0,
+                clockwise,
+                "from {angle}, velocity {}",
+                state.rotation_velocity
+            );
+        }
+    }
+
+    #[test]
+    fn committed_turn_keeps_its_direction() {
+        // more than half way round, but told to go clockwise
+        let state = PaddleState {
+            angle: 10.0,
+            next_stop: 200.0,
+            rotating: RotatingM::Clockwise,
+            ..default()
+        };
+        let state = step_paddle(
+            state,
+            &PaddleInput::default(),
+            &PaddleTuning::default(),
+            DELTA,
+        );
+        assert!(state.rotation_velocity < 0.0);
+        assert!(state.angle > 10.0);
+    }
+
+    #[test]
+    fn double_tap_turns_two_steps() {
+        let mut state = at(0.0);
+        press(&mut state, true);
+        press(&mut state, true);
+        let state = step_paddle(
+            state,
+            &PaddleInput::default(),
+            &PaddleTuning::default(),
+            DELTA,
+        );
+        assert_eq!(state.next_stop, 180.0);
+        assert!(state.queue.is_empty());
+        assert_eq!(settle(state).angle, 180.0);
+    }
+
+    #[test]
+    fn stale_presses_are_dropped() {
+        let mut state = at(0.0);
+        state.queue.push_back(QueuedRotation {
+            clockwise: true,
+            age: ROTATION_BUFFER,
+        });
+        let state = step_paddle(
+            state,
+            &PaddleInput::default(),
+            &PaddleTuning::default(),
+            DELTA,
+        );
+        assert!(state.queue.is_empty());
+        assert_eq!(state.next_stop, 0.0);
+    }
+
+    #[test]
+    fn smaller_steps_stop_sooner() {
+        let tuning = PaddleTuning {
+            rotation_step: RotationStep::Step(45.0),
+            ..default()
+        };
+        let mut state = at(0.0);
+        press(&mut state, false);
+        let state = step_paddle(state, &PaddleInput::default(), &tuning, DELTA);
+        assert_eq!(state.next_stop, 315.0);
+    }
+
+    #[test]
+    fn free_rotation_spins_up_and_down() {
+        let tuning = PaddleTuning {
+            rotation_step: RotationStep::Free,
+            ..default()
+        };
+        let held = PaddleInput {
+            anticlockwise: true,
+            ..default()
+        };
+        let mut state = step_paddle(at(0.0), &held, &tuning, DELTA);
+        assert!(state.angle > 350.0, "anticlockwise wraps below 0°");
+        for _ in 0..30 {
+            state = step_paddle(state, &held, &tuning, DELTA);
+        }
+        assert_eq!(state.rotating, RotatingM::AntiClockwise);
+        let top_speed = (2.0 * tuning.rot_acceleration * FREE_ROTATION_TURN).sqrt();
+        assert!((state.rotation_velocity - top_speed).abs() < 1e-6);
+        for _ in 0..30 {
+            state = step_paddle(state, &PaddleInput::default(), &tuning, DELTA);
+        }
+        assert_eq!(state.rotation_velocity, 0.0);
+        assert_eq!(state.rotating, RotatingM::Neither);
+    }
+
+    #[test]
+    fn direction_buttons_and_stick_accelerate() {
+        let tuning = PaddleTuning::default();
+        let input = PaddleInput {
+            direction: Vec2::new(1.0, -1.0),
+            ..default()
+        };
+        let state = step_paddle(at(0.0), &input, &tuning, DELTA);
+        assert_eq!(state.velocity, Vec2::new(1.0, -1.0) * tuning.acceleration);
+
+        let input = PaddleInput {
+            stick: Vec2::new(0.0, STICK_DEADZONE / 2.0),
+            ..default()
+        };
+        assert_eq!(
+            step_paddle(at(0.0), &input, &tuning, DELTA).velocity,
+            Vec2::ZERO
+        );
+        let input = PaddleInput {
+            stick: Vec2::Y,
+            ..default()
+        };
+        let velocity = step_paddle(at(0.0), &input, &tuning, DELTA).velocity;
+        assert!((velocity - Vec2::Y * tuning.acceleration).length() < 1e-3);
     }
 }
@@ -0,0 +1,346 @@
+//! Hashes the state of a match every fixed tick, so two runs of the same
+//! match can be checked for the exact tick they stopped agreeing.
+
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+};
+
+use bevy::prelude::*;
+use bevy_rapier2d::prelude::{PhysicsSet, Velocity};
+
+use crate::{
+    game::{serve_ball, Score},
+    player::Player,
+    AppState,
+    Ball,
+    GameTick,
+    Paddle,
+};
+
+/// positions and velocities are rounded to this many units before hashing,
+/// so floating point noise below it doesn't count as a desync
+const POSITION_STEP: f32 = 0.01;
+/// same for rotations, as quaternion components
+const ROTATION_STEP: f32 = 0.0001;
+
+/// Adds a [`TickChecksum`] event and updates [`Checksums`] every fixed tick
+/// of a match, optionally also writing them to a log file.
+#[derive(Default)]
+pub struct ChecksumPlugin {
+    pub log: Option<PathBuf>,
+}
+
+impl Plugin for ChecksumPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<TickChecksum>()
+            .init_resource::<Checksums>()
+            .add_systems(
+                GameTick,
+                checksum_tick
+                    .after(serve_ball)
+                    .after(PhysicsSet::Writeback)
+                    .run_if(in_state(AppState::InGame)),
+            );
+        if let Some(path) = &self.log {
+            match File::create(path) {
+                Ok(file) => {
+                    app.insert_resource(ChecksumLog(BufWriter::new(file)))
+                        .add_systems(GameTick, write_checksum_log.after(checksum_tick));
+                }
+                Err(e) => warn!("couldn't create checksum log {}: {e}", path.display()),
+            }
+        }
+    }
+}
+
+/// Something in the match that gets its own hash.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Body {
+    Ball,
+    Paddle(usize),
+    Score,
+}
+
+impl fmt::Display for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ball => write!(f, "ball"),
+            Self::Paddle(player) => write!(f, "paddle{player}"),
+            Self::Score => write!(f, "score"),
+        }
+    }
+}
+
+/// The hashes of one fixed tick.
+#[derive(Event, Clone, PartialEq, Eq, Debug)]
+pub struct TickChecksum {
+    /// counted from the start of the match
+    pub tick: u64,
+    /// everything in `bodies` hashed together
+    pub hash: u64,
+    pub bodies: Vec<(Body, u64)>,
+}
+
+impl fmt::Display for TickChecksum {
+    /// One line of a checksum log.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {:016x}", self.tick, self.hash)?;
+        for (body, hash) in &self.bodies {
+            write!(f, " {body}:{hash:016x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The latest [`TickChecksum`], and the tick the next one is for.
+#[derive(Resource, Default, Debug)]
+pub struct Checksums {
+    pub tick: u64,
+    pub latest: Option<TickChecksum>,
+}
+
+#[derive(Resource)]
+struct ChecksumLog(BufWriter<File>);
+
+/// FNV-1a, which unlike the std hasher is guaranteed to be the same
+/// everywhere, so logs from different machines and builds can be compared.
+#[derive(Clone, Copy)]
+struct Fnv(u64);
+
+impl Fnv {
+    const fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn write_f32(&mut self, value: f32, step: f32) {
+        // -0.0 and 0.0 round to the same thing
+        let quantized = (value / step).round() as i64;
+        self.write(&quantized.to_le_bytes());
+    }
+
+    fn write_vec2(&mut self, value: Vec2, step: f32) {
+        self.write_f32(value.x, step);
+        self.write_f32(value.y, step);
+    }
+}
+
+fn hash_body(transform: &Transform, velocity: &Velocity) -> u64 {
+    let mut hasher = Fnv::new();
+    hasher.write_vec2(transform.translation.truncate(), POSITION_STEP);
+    for component in transform.rotation.to_array() {
+        hasher.write_f32(component, ROTATION_STEP);
+    }
+    hasher.write_vec2(velocity.linvel, POSITION_STEP);
+    hasher.write_f32(velocity.angvel, ROTATION_STEP);
+    hasher.0
+}
+
+/// Hashes the ball, every paddle and the score at the end of a tick.
+pub fn checksum_tick(
+    mut checksums: ResMut<Checksums>,
+    score: Res<Score>,
+    ball_query: Query<(&Transform, &Velocity), With<Ball>>,
+    paddle_query: Query<(&Player, &Transform, &Velocity), With<Paddle>>,
+    mut events: EventWriter<TickChecksum>,
+) {
+    // a new match starts counting again
+    if score.is_added() {
+        checksums.tick = 0;
+    }
+
+    let mut bodies = Vec::new();
+    for (transform, velocity) in &ball_query {
+        bodies.push((Body::Ball, hash_body(transform, velocity)));
+    }
+    let mut paddles: Vec<_> = paddle_query.iter().collect();
+    paddles.sort_by_key(|(player, _, _)| player.0);
+    for (player, transform, velocity) in paddles {
+        bodies.push((Body::Paddle(player.0), hash_body(transform, velocity)));
+    }
+    let mut hasher = Fnv::new();
+    hasher.write(&score.left.to_le_bytes());
+    hasher.write(&score.right.to_le_bytes());
+    bodies.push((Body::Score, hasher.0));
+
+    let mut hasher = Fnv::new();
+    for (_, hash) in &bodies {
+        hasher.write(&hash.to_le_bytes());
+    }
+    let checksum = TickChecksum {
+        tick: checksums.tick,
+        hash: hasher.0,
+        bodies,
+    };
+    checksums.tick += 1;
+    events.send(checksum.clone());
+    checksums.latest = Some(checksum);
+}
+
+fn write_checksum_log(mut log: ResMut<ChecksumLog>, mut events: EventReader<TickChecksum>) {
+    for checksum in events.read() {
+        if let Err(e) = writeln!(log.0, "{checksum}") {
+            warn!("couldn't write checksum log: {e}");
+        }
+    }
+    if let Err(e) = log.0.flush() {
+        warn!("couldn't write checksum log: {e}");
+    }
+}
+
+/// Where two checksum logs first disagree.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Divergence {
+    /// `body` hashed differently on this tick
+    Body { tick: u64, body: String },
+    /// one log has something on this tick the other doesn't
+    Missing { tick: u64, body: String },
+    /// one log stops before the other
+    Length { tick: u64 },
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Body { tick, body } => write!(f, "tick {tick}: {body} diverged"),
+            Self::Missing { tick, body } => write!(f, "tick {tick}: {body} is only in one log"),
+            Self::Length { tick } => write!(f, "tick {tick}: only one log goes this far"),
+        }
+    }
+}
+
+/// A line of a checksum log, with the hashes left as text.
+struct LogLine<'a> {
+    tick: u64,
+    hash: &'a str,
+    bodies: Vec<(&'a str, &'a str)>,
+}
+
+fn parse_line(line: &str) -> io::Result<LogLine<'_>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("bad line: {line}"));
+    let mut words = line.split_whitespace();
+    let tick = words
+        .next()
+        .and_then(|tick| tick.parse().ok())
+        .ok_or_else(invalid)?;
+    let hash = words.next().ok_or_else(invalid)?;
+    let bodies = words
+        .map(|word| word.split_once(':').ok_or_else(invalid))
+        .collect::<io::Result<_>>()?;
+    Ok(LogLine { tick, hash, bodies })
+}
+
+/// Finds the first tick and body where two checksum logs differ, or `None`
+/// if they match all the way through.
+pub fn compare_logs(a: &str, b: &str) -> io::Result<Option<Divergence>> {
+    let mut a_lines = a.lines().filter(|line| !line.trim().is_empty());
+    let mut b_lines = b.lines().filter(|line| !line.trim().is_empty());
+    loop {
+        let (a_line, b_line) = match (a_lines.next(), b_lines.next()) {
+            (None, None) => return Ok(None),
+            (Some(line), None) | (None, Some(line)) => {
+                let tick = parse_line(line)?.tick;
+                return Ok(Some(Divergence::Length { tick }));
+            }
+            (Some(a_line), Some(b_line)) => (parse_line(a_line)?, parse_line(b_line)?),
+        };
+        let tick = a_line.tick.min(b_line.tick);
+        if a_line.tick != b_line.tick {
+            return Ok(Some(Divergence::Length { tick }));
+        }
+        if a_line.hash == b_line.hash && a_line.bodies == b_line.bodies {
+            continue;
+        }
+        for (body, hash) in &a_line.bodies {
+            match b_line.bodies.iter().find(|(other, _)| other == body) {
+                Some((_, other_hash)) if other_hash == hash => {}
+                Some(_) => {
+                    return Ok(Some(Divergence::Body {
+                        tick,
+                        body: body.to_string(),
+                    }))
+                }
+                None => {
+                    return Ok(Some(Divergence::Missing {
+                        tick,
+                        body: body.to_string(),
+                    }))
+                }
+            }
+        }
+        let extra = b_line
+            .bodies
+            .iter()
+            .find(|(body, _)| !a_line.bodies.iter().any(|(other, _)| other == body))
+            .map_or("everything", |(body, _)| body);
+        return Ok(Some(Divergence::Missing {
+            tick,
+            body: extra.to_string(),
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{headless, match_setup::MatchConfig, Action};
+
+    fn run(press: Option<(usize, Action)>, ticks: usize) -> Vec<String> {
+        let mut app = headless::headless_app(MatchConfig::default());
+        app.add_plugins(ChecksumPlugin::default());
+        headless::start_match(&mut app);
+        let mut lines = Vec::new();
+        for tick in 0..ticks {
+            if tick == 30 {
+                if let Some((player, action)) = press {
+                    headless::press(&mut app, player, action);
+                }
+            }
+            app.update();
+            let checksums = app.world.resource::<Checksums>();
+            lines.extend(checksums.latest.as_ref().map(ToString::to_string));
+        }
+        lines
+    }
+
+    #[test]
+    fn same_inputs_give_the_same_checksums() {
+        let a = run(None, 100);
+        let b = run(None, 100);
+        assert!(a.len() >= 99, "checksummed every tick");
+        assert_eq!(compare_logs(&a.join("\n"), &b.join("\n")).unwrap(), None);
+    }
+
+    #[test]
+    fn finds_the_first_divergent_tick_and_body() {
+        let a = run(None, 100);
+        let b = run(Some((1, Action::Up)), 100);
+        let Some(Divergence::Body { tick, body }) =
+            compare_logs(&a.join("\n"), &b.join("\n")).unwrap()
+        else {
+            panic!("didn't diverge");
+        };
+        assert_eq!(body, "paddle1");
+        assert!((29..=32).contains(&tick), "diverged on tick {tick}");
+    }
+
+    #[test]
+    fn shorter_log_is_reported() {
+        let a = "0 01 ball:01\n1 02 ball:02\n";
+        let b = "0 01 ball:01\n";
+        assert_eq!(
+            compare_logs(a, b).unwrap(),
+            Some(Divergence::Length { tick: 1 })
+        );
+        assert!(compare_logs("nonsense", "0 01").is_err());
+    }
+}
@@ -0,0 +1,468 @@
+//! Plays a match hosted by a [`Server`](crate::server::Server). Nothing is
+//! simulated here. The local player's input goes to the server, and the
+//! states it sends back are drawn a few ticks late, blending between the
+//! two either side so movement stays smooth between packets.
+
+use std::{
+    collections::VecDeque,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+use leafwing_input_manager::prelude::ActionState;
+
+use crate::{
+    game::{despawn_game, GameEntity, Score, ScoreText},
+    net::{take_over_paddles, NetInput},
+    player::Player,
+    rules::MatchProgress,
+    server::{ClientMessage, MatchState, ServerMessage, MAX_ROOM_NAME},
+    Action,
+    AppState,
+    Ball,
+    Paddle,
+};
+
+/// States are drawn this many ticks behind the newest one, so a late or
+/// lost packet doesn't leave nothing to blend towards.
+pub const INTERPOLATION_DELAY: f64 = 3.0;
+/// how long to wait for a welcome before asking to join again
+const JOIN_RETRY: Duration = Duration::from_millis(500);
+
+/// Plays through the [`ServerConnection`], when there is one.
+pub struct ClientPlugin;
+
+impl Plugin for ClientPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            Update,
+            client_update.run_if(resource_exists::<ServerConnection>()),
+        )
+        // the match is still running the frame the server closes it
+        .add_systems(
+            OnEnter(AppState::MainMenu),
+            clear_closed_match.run_if(resource_exists::<ClosedMatch>()),
+        )
+        .add_systems(OnExit(AppState::MatchOver), forget_server_room);
+    }
+}
+
+/// Left behind when the server closes a match, so it's cleared away once the
+/// menu is up and nothing is using it any more.
+#[derive(Resource)]
+struct ClosedMatch;
+
+/// Where the last server match was played, so a rematch goes back to that
+/// room instead of starting a match here.
+#[derive(Resource, Clone, Debug)]
+pub struct ServerRoom {
+    pub server: SocketAddr,
+    pub room: String,
+}
+
+/// A match on a server, in a room shared with one other client.
+#[derive(Resource)]
+pub struct ServerConnection {
+    socket: UdpSocket,
+    server: SocketAddr,
+    room: String,
+    /// set once the server has let us in
+    player: Option<usize>,
+    tick_rate: f64,
+    last_join: Option<Instant>,
+    /// numbers the inputs we send
+    seq: u32,
+    /// received states not yet drawn past, oldest first
+    states: VecDeque<MatchState>,
+    /// the tick being drawn, part way between two states
+    render_tick: Option<f64>,
+}
+
+impl ServerConnection {
+    /// Joins `room` on the server at `server`.
+    pub fn connect(server: SocketAddr, room: impl Into<String>) -> io::Result<Self> {
+        let room = room.into();
+        if room.len() > MAX_ROOM_NAME {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("room names are at most {MAX_ROOM_NAME} bytes"),
+            ));
+        }
+        let local: SocketAddr = if server.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            server,
+            room,
+            player: None,
+            tick_rate: 0.0,
+            last_join: None,
+            seq: 0,
+            states: VecDeque::new(),
+            render_tick: None,
+        })
+    }
+
+    /// Which player the server has made us, once it has.
+    pub const fn player(&self) -> Option<usize> {
+        self.player
+    }
+
+    fn send(&self, message: &ClientMessage) {
+        if let Err(e) = self.socket.send_to(&message.encode(), self.server) {
+            warn!("couldn't send to server: {e}");
+        }
+    }
+
+    fn receive(&mut self) -> Vec<ServerMessage> {
+        let mut messages = Vec::new();
+        let mut buffer = [0; 1024];
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                // e.g. the server isn't up yet
+                Err(e) if e.kind() == io::ErrorKind::ConnectionReset => continue,
+                Err(e) => {
+                    warn!("couldn't receive from server: {e}");
+                    break;
+                }
+            };
+            if from != self.server {
+                continue;
+            }
+            messages.extend(ServerMessage::decode(&buffer[..len]));
+        }
+        messages
+    }
+
+    fn push_state(&mut self, state: MatchState) {
+        // arrived after a newer one, so it's no use
+        if self
+            .states
+            .back()
+            .is_some_and(|last| last.tick >= state.tick)
+        {
+            return;
+        }
+        self.states.push_back(state);
+    }
+
+    /// The state to draw `delta` seconds after the last one, blended from
+    /// the received states either side of it.
+    fn interpolated(&mut self, delta: f32) -> Option<MatchState> {
+        let newest = f64::from(self.states.back()?.tick);
+        let target = newest - INTERPOLATION_DELAY;
+        let render = self
+            .render_tick
+            .map_or(target, |tick| tick + f64::from(delta) * self.tick_rate);
+        // too far off after a stall or a burst of packets, so start again
+        let render = if (render - target).abs() > INTERPOLATION_DELAY * 2.0 {
+            target
+        } else {
+            render.min(newest)
+        };
+        self.render_tick = Some(render);
+
+        while self
+            .states
+            .get(1)
+            .is_some_and(|next| f64::from(next.tick) <= render)
+        {
+            self.states.pop_front();
+        }
+        let from = &self.states[0];
+        let Some(to) = self.states.get(1) else {
+            return Some(from.clone());
+        };
+        let t = ((render - f64::from(from.tick)) / f64::from(to.tick - from.tick)).clamp(0.0, 1.0)
+            as f32;
+        Some(MatchState {
+            ball: from.ball.lerp(to.ball, t),
+            paddles: from
+                .paddles
+                .iter()
+                .zip(&to.paddles)
+                .map(|(from, to)| from.lerp(*to, t))
+                .collect(),
+            ..from.clone()
+        })
+    }
+}
+
+impl Drop for ServerConnection {
+    fn drop(&mut self) {
+        // the server would notice eventually, but the other player needn't wait
+        if self.player.is_some() {
+            self.send(&ClientMessage::Leave);
+        }
+    }
+}
+
+/// Moves the match's paddles, ball and score to where the server says.
+fn show_state(world: &mut World, state: &MatchState) {
+    let mut balls = world.query_filtered::<&mut Transform, With<Ball>>();
+    for mut transform in balls.iter_mut(world) {
+        state.ball.apply_to(&mut transform);
+    }
+    let mut paddles = world.query_filtered::<(&Player, &mut Transform), With<Paddle>>();
+    for (player, mut transform) in paddles.iter_mut(world) {
+        if let Some(pose) = state.paddles.get(player.0) {
+            pose.apply_to(&mut transform);
+        }
+    }
+    if let Some(mut progress) = world.get_resource_mut::<MatchProgress>() {
+        progress.sets = state.sets;
+    }
+    match world.get_resource_mut::<Score>() {
+        Some(mut score) if *score != state.score => *score = state.score,
+        _ => return,
+    }
+    let mut texts = world.query_filtered::<&mut Text, With<ScoreText>>();
+    for mut text in texts.iter_mut(world) {
+        text.sections[0].value = format!("{} - {}", state.score.left, state.score.right);
+    }
+}
+
+fn clear_closed_match(mut commands: Commands, query: Query<Entity, With<GameEntity>>) {
+    despawn_game(&mut commands, &query);
+    commands.remove_resource::<ClosedMatch>();
+}
+
+fn forget_server_room(mut commands: Commands) {
+    commands.remove_resource::<ServerRoom>();
+}
+
+/// Talks to the server once a frame: joins the room, sends the local input
+/// and draws the latest states.
+fn client_update(world: &mut World) {
+    let delta = world.resource::<Time>().delta_seconds();
+    let state = *world.resource::<State<AppState>>().get();
+    let done = world.resource_scope(|world, mut connection: Mut<ServerConnection>| {
+        let Some(player) = connection.player else {
+            let waited = connection
+                .last_join
+                .is_none_or(|last| last.elapsed() >= JOIN_RETRY);
+            if waited {
+                connection.send(&ClientMessage::Join {
+                    room: connection.room.clone(),
+                });
+                connection.last_join = Some(Instant::now());
+            }
+            for message in connection.receive() {
+                match message {
+                    ServerMessage::Welcome { player, tick_rate } => {
+                        info!("joined room {} as player {}", connection.room, player + 1);
+                        connection.player = Some(usize::from(player));
+                        connection.tick_rate = f64::from(tick_rate);
+                        world
+                            .resource_mut::<NextState<AppState>>()
+                            .set(AppState::InGame);
+                    }
+                    ServerMessage::Full => {
+                        warn!("room {} is full", connection.room);
+                        world
+                            .resource_mut::<NextState<AppState>>()
+                            .set(AppState::MainMenu);
+                        return true;
+                    }
+                    _ => {}
+                }
+            }
+            return false;
+        };
+
+        for message in connection.receive() {
+            match message {
+                ServerMessage::State(state) => connection.push_state(state),
+                ServerMessage::Over(result) => {
+                    world.insert_resource(result);
+                    world.insert_resource(ServerRoom {
+                        server: connection.server,
+                        room: connection.room.clone(),
+                    });
+                    world
+                        .resource_mut::<NextState<AppState>>()
+                        .set(AppState::MatchOver);
+                    return true;
+                }
+                ServerMessage::Closed => {
+                    info!("the other player left");
+                    world.insert_resource(ClosedMatch);
+                    world
+                        .resource_mut::<NextState<AppState>>()
+                        .set(AppState::MainMenu);
+                    return true;
+                }
+                ServerMessage::Welcome { .. } | ServerMessage::Full => {}
+            }
+        }
+
+        // still sent while paused, so the server knows we're here
+        let input = if state == AppState::InGame {
+            take_over_paddles(world, Some(player));
+            world
+                .get_resource::<ActionState<Action>>()
+                .map(NetInput::from_action_state)
+                .unwrap_or_default()
+        } else {
+            NetInput::default()
+        };
+        connection.seq += 1;
+        let seq = connection.seq;
+        connection.send(&ClientMessage::Input { seq, input });
+        if let Some(state) = connection.interpolated(delta) {
+            show_state(world, &state);
+        }
+        false
+    });
+    if done {
+        world.remove_resource::<ServerConnection>();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use leafwing_input_manager::prelude::InputMap;
+
+    use super::*;
+    use crate::{
+        headless::headless_app,
+        match_setup::MatchConfig,
+        rules::match_over_update,
+        server::Server,
+        MenuButtonPressed,
+    };
+
+    fn client(server: SocketAddr, room: &str) -> App {
+        let mut app = headless_app(MatchConfig::default());
+        app.add_plugins(ClientPlugin)
+            .insert_resource(ServerConnection::connect(server, room).unwrap());
+        app
+    }
+
+    /// Updates every client, then plays a server tick.
+    fn play(server: &mut Server, clients: &mut [&mut App], ticks: usize) {
+        for _ in 0..ticks {
+            for client in clients.iter_mut() {
+                client.update();
+            }
+            server.receive();
+            server.tick();
+        }
+    }
+
+    fn player(app: &App) -> Option<usize> {
+        app.world
+            .get_resource::<ServerConnection>()
+            .and_then(ServerConnection::player)
+    }
+
+    fn paddle(app: &mut App, player: usize) -> Vec3 {
+        let mut query = app.world.query::<(&Player, &Transform)>();
+        query
+            .iter(&app.world)
+            .find(|(p, _)| p.0 == player)
+            .map(|(_, transform)| transform.translation)
+            .expect("no paddle for that player")
+    }
+
+    fn app_state(app: &App) -> AppState {
+        *app.world.resource::<State<AppState>>().get()
+    }
+
+    #[test]
+    fn clients_see_the_servers_match() {
+        let mut server = Server::bind("127.0.0.1:0", 60).unwrap();
+        let addr = server.local_addr().unwrap();
+        let (mut a, mut b) = (client(addr, "room"), client(addr, "room"));
+        play(&mut server, &mut [&mut a, &mut b], 10);
+        assert_eq!((player(&a), player(&b)), (Some(0), Some(1)));
+        assert_eq!(app_state(&a), AppState::InGame);
+        let start = paddle(&mut a, 0);
+
+        // only the server moves paddles, so hold up on a's side and watch
+        // both clients
+        a.world.remove_resource::<InputMap<Action>>();
+        a.world
+            .resource_mut::<ActionState<Action>>()
+            .press(Action::Up);
+        play(&mut server, &mut [&mut a, &mut b], 30);
+        let (on_a, on_b) = (paddle(&mut a, 0), paddle(&mut b, 0));
+        assert!(on_a.y > start.y + 10.0, "{start} -> {on_a}");
+        assert!((on_a - on_b).length() < 1.0, "{on_a} vs {on_b}");
+        assert_eq!(paddle(&mut b, 1), paddle(&mut a, 1));
+    }
+
+    #[test]
+    fn rooms_fill_up_and_close() {
+        let mut server = Server::bind("127.0.0.1:0", 60).unwrap();
+        let addr = server.local_addr().unwrap();
+        let (mut a, mut b, mut c) = (
+            client(addr, "room"),
+            client(addr, "room"),
+            client(addr, "room"),
+        );
+        let mut d = client(addr, "other");
+        play(&mut server, &mut [&mut a, &mut b], 5);
+        play(&mut server, &mut [&mut a, &mut b, &mut c, &mut d], 5);
+        assert_eq!(server.rooms(), 2);
+        // turned away from the full room
+        assert!(!c.world.contains_resource::<ServerConnection>());
+        assert_eq!(app_state(&c), AppState::MainMenu);
+
+        // a leaving ends the match for b, but not the other room
+        a.world.remove_resource::<ServerConnection>();
+        play(&mut server, &mut [&mut b, &mut d], 5);
+        assert_eq!(server.rooms(), 1);
+        assert!(!b.world.contains_resource::<ServerConnection>());
+        assert_eq!(app_state(&b), AppState::MainMenu);
+        assert!(!b.world.contains_resource::<Score>());
+        let mut game = b.world.query_filtered::<(), With<GameEntity>>();
+        assert_eq!(game.iter(&b.world).count(), 0);
+        assert_eq!(player(&d), Some(0));
+    }
+
+    #[test]
+    fn rematches_go_back_to_the_room() {
+        let mut server = Server::bind("127.0.0.1:0", 60).unwrap();
+        let addr = server.local_addr().unwrap();
+        let (mut a, mut b) = (client(addr, "room"), client(addr, "room"));
+        play(&mut server, &mut [&mut a, &mut b], 10);
+        // as the server does once the match is won
+        for app in [&mut a, &mut b] {
+            app.add_event::<MenuButtonPressed>().add_systems(
+                Update,
+                match_over_update.run_if(in_state(AppState::MatchOver)),
+            );
+            app.world.remove_resource::<ServerConnection>();
+            app.world.insert_resource(ServerRoom {
+                server: addr,
+                room: "room".to_string(),
+            });
+            app.world
+                .resource_mut::<NextState<AppState>>()
+                .set(AppState::MatchOver);
+        }
+        play(&mut server, &mut [&mut a, &mut b], 2);
+        assert_eq!(server.rooms(), 0);
+
+        for app in [&mut a, &mut b] {
+            app.world
+                .send_event(MenuButtonPressed("rematch".to_string()));
+        }
+        play(&mut server, &mut [&mut a, &mut b], 10);
+        assert_eq!(server.rooms(), 1);
+        for app in [&a, &b] {
+            assert_eq!(app_state(app), AppState::InGame);
+            assert!(player(app).is_some());
+        }
+    }
+}
@@ -0,0 +1,347 @@
+use bevy::prelude::*;
+use leafwing_input_manager::{prelude::*, user_input::InputKind};
+
+use crate::{
+    game::Score,
+    match_setup::MAX_PLAYERS,
+    player::{conflict_warning, default_input_map, Bindings, Controller, Roster},
+    spawn_menu_button,
+    Action,
+    AppState,
+    MenuButtonId,
+    MenuButtonPressed,
+    TopLevelNode,
+};
+
+/// Which kind of input a binding button on the controls screen sets.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Device {
+    Keyboard,
+    Gamepad,
+}
+
+impl Device {
+    fn matches(self, input: &UserInput) -> bool {
+        let raw = input.raw_inputs();
+        match self {
+            Self::Keyboard => !raw.keycodes.is_empty(),
+            Self::Gamepad => !raw.gamepad_buttons.is_empty(),
+        }
+    }
+}
+
+/// What the controls screen is showing.
+#[derive(Resource, Default, Debug)]
+pub struct ControlsScreen {
+    pub player: usize,
+    /// the binding waiting for a key or button press
+    pub listening: Option<(Action, Device)>,
+    status: String,
+}
+
+#[derive(Component)]
+pub struct ControlsStatus;
+
+/// Lists keys bound for more than one player, like the setup screen does.
+#[derive(Component)]
+pub struct ControlsWarning;
+
+const fn action_label(action: Action) -> &'static str {
+    match action {
+        Action::Left => "left",
+        Action::Right => "right",
+        Action::Up => "up",
+        Action::Down => "down",
+        Action::RotateClockwise => "rotate cw",
+        Action::RotateAntiClockwise => "rotate acw",
+        Action::Move => "move",
+    }
+}
+
+fn input_label(input: &UserInput) -> String {
+    let raw = input.raw_inputs();
+    raw.keycodes
+        .first()
+        .map(|key| format!("{key:?}"))
+        .or_else(|| {
+            raw.gamepad_buttons
+                .first()
+                .map(|button| format!("{button:?}"))
+        })
+        .unwrap_or_default()
+        .to_lowercase()
+}
+
+/// Binds `input` to `action`, replacing whatever `action` had on that device.
+/// Returns the action that `input` was taken away from, if any.
+fn rebind(
+    input_map: &mut InputMap<Action>,
+    action: Action,
+    device: Device,
+    input: InputKind,
+) -> Option<Action> {
+    let conflict = Action::variants()
+        .filter(|other| *other != action)
+        .find(|other| input_map.remove(*other, input).is_some());
+    if let Some(inputs) = input_map.get_mut(action) {
+        inputs.retain(|existing| !device.matches(existing));
+    }
+    input_map.insert(input, action);
+    conflict
+}
+
+/// Parses a button id like `controls_key_3` into the action and device it binds.
+fn binding_button(id: &str) -> Option<(Action, Device)> {
+    let (device, index) = if let Some(index) = id.strip_prefix("controls_key_") {
+        (Device::Keyboard, index)
+    } else {
+        (Device::Gamepad, id.strip_prefix("controls_pad_")?)
+    };
+    Some((Action::get_at(index.parse().ok()?)?, device))
+}
+
+impl ControlsScreen {
+    /// What the button with this id should currently say.
+    fn label(&self, bindings: &Bindings, id: &str) -> Option<String> {
+        if id == "controls_player" {
+            return Some(format!("p{}", self.player + 1));
+        }
+        let (action, device) = binding_button(id)?;
+        if self.listening == Some((action, device)) {
+            return Some("...".to_string());
+        }
+        let input_map = bindings.get(self.player);
+        Some(
+            input_map
+                .get(action)
+                .and_then(|inputs| inputs.iter().find(|input| device.matches(input)))
+                .map_or("-".to_string(), input_label),
+        )
+    }
+}
+
+pub fn controls_menu(mut commands: Commands, server: Res<AssetServer>, bindings: Res<Bindings>) {
+    let blazma: Handle<Font> = server.load("Blazma/Blazma-Regular.ttf");
+    let screen = ControlsScreen::default();
+    let text_style = TextStyle {
+        font: blazma.clone(),
+        font_size: 30.0,
+        color: Color::rgb(0.9, 0.9, 0.9),
+    };
+
+    let node = commands
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    width: Val::Percent(100.0),
+                    height: Val::Percent(100.0),
+                    flex_direction: FlexDirection::Column,
+                    align_items: AlignItems::Center,
+                    justify_content: JustifyContent::Center,
+                    row_gap: Val::Px(5.0),
+                    ..default()
+                },
+                // covers the game when opened from the pause menu
+                background_color: Color::rgb(0.05, 0.05, 0.05).into(),
+                ..default()
+            },
+            TopLevelNode,
+        ))
+        .id();
+
+    let row = |commands: &mut Commands, label: &str| {
+        let row = commands
+            .spawn(NodeBundle {
+                style: Style {
+                    align_items: AlignItems::Center,
+                    column_gap: Val::Px(5.0),
+                    ..default()
+                },
+                ..default()
+            })
+            .with_children(|parent| {
+                parent.spawn(
+                    TextBundle::from_section(label, text_style.clone()).with_style(Style {
+                        width: Val::Px(160.0),
+                        ..default()
+                    }),
+                );
+            })
+            .id();
+        commands.entity(node).add_child(row);
+        row
+    };
+
+    let player_row = row(&mut commands, "player");
+    spawn_menu_button(
+        &mut commands,
+        player_row,
+        &format!("p{}", screen.player + 1),
+        blazma.clone(),
+        Some("controls_player".to_string()),
+    );
+    // the stick is always the stick
+    for action in Action::variants().filter(|action| *action != Action::Move) {
+        let action_row = row(&mut commands, action_label(action));
+        for prefix in ["controls_key", "controls_pad"] {
+            let id = format!("{prefix}_{}", action.index());
+            spawn_menu_button(
+                &mut commands,
+                action_row,
+                &screen.label(&bindings, &id).unwrap_or_default(),
+                blazma.clone(),
+                Some(id),
+            );
+        }
+    }
+
+    commands.entity(node).with_children(|parent| {
+        parent.spawn((
+            TextBundle::from_section("", text_style.clone()),
+            ControlsStatus,
+        ));
+        parent.spawn((
+            TextBundle::from_section(
+                conflict_warning(&bindings.keyboard_conflicts()),
+                TextStyle {
+                    font: blazma.clone(),
+                    font_size: 20.0,
+                    color: Color::rgb(1.0, 0.6, 0.3),
+                },
+            ),
+            ControlsWarning,
+        ));
+    });
+    let buttons = row(&mut commands, "");
+    spawn_menu_button(
+        &mut commands,
+        buttons,
+        "reset",
+        blazma.clone(),
+        Some("controls_reset".to_string()),
+    );
+    spawn_menu_button(
+        &mut commands,
+        buttons,
+        "back",
+        blazma,
+        Some("controls_back".to_string()),
+    );
+
+    commands.insert_resource(screen);
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn controls_update(
+    mut menu_button_pressed: EventReader<MenuButtonPressed>,
+    mut screen: ResMut<ControlsScreen>,
+    mut bindings: ResMut<Bindings>,
+    roster: Option<ResMut<Roster>>,
+    score: Option<Res<Score>>,
+    keys: Res<Input<KeyCode>>,
+    gamepad_buttons: Res<Input<GamepadButton>>,
+    mut next_state: ResMut<NextState<AppState>>,
+    button_query: Query<(&MenuButtonId, &Children)>,
+    mut text_query: Query<&mut Text>,
+    status_query: Query<Entity, With<ControlsStatus>>,
+    warning_query: Query<Entity, With<ControlsWarning>>,
+) {
+    if let Some((action, device)) = screen.listening {
+        let input = match device {
+            Device::Keyboard => keys
+                .get_just_pressed()
+                .next()
+                .map(|key| InputKind::from(*key)),
+            Device::Gamepad => gamepad_buttons
+                .get_just_pressed()
+                .next()
+                .map(|button| InputKind::from(button.button_type)),
+        };
+        if keys.just_pressed(KeyCode::Escape) {
+            screen.listening = None;
+            screen.status = String::new();
+        } else if let Some(input) = input {
+            let player = screen.player;
+            let conflict = rebind(&mut bindings.0[player], action, device, input);
+            screen.listening = None;
+            screen.status = conflict.map_or_else(String::new, |other| {
+                format!(
+                    "{} moved from {}",
+                    input_label(&UserInput::Single(input)),
+                    action_label(other)
+                )
+            });
+        }
+    }
+
+    for MenuButtonPressed(id) in menu_button_pressed.read() {
+        match id.as_str() {
+            "controls_player" => {
+                screen.player = (screen.player + 1) % MAX_PLAYERS;
+                screen.listening = None;
+                screen.status = String::new();
+            }
+            "controls_reset" => {
+                let player = screen.player;
+                bindings.0[player] = default_input_map(player);
+                screen.listening = None;
+                screen.status = "reset to defaults".to_string();
+            }
+            // back to wherever the screen was opened from
+            "controls_back" => next_state.set(if score.is_some() {
+                AppState::Paused
+            } else {
+                AppState::MainMenu
+            }),
+            id => {
+                if let Some(binding) = binding_button(id) {
+                    screen.listening = Some(binding);
+                    screen.status = match binding.1 {
+                        Device::Keyboard => "press a key, escape to cancel",
+                        Device::Gamepad => "press a gamepad button, escape to cancel",
+                    }
+                    .to_string();
+                }
+            }
+        }
+    }
+
+    if bindings.is_changed() {
+        // a paused match picks up the new controls when it resumes
+        if let Some(mut roster) = roster {
+            for (player, config) in roster.0.iter_mut().enumerate() {
+                if let Controller::Human(input_map) = &mut config.controller {
+                    *input_map = bindings.get(player);
+                }
+            }
+            for conflict in roster.resolve_conflicts() {
+                warn!(
+                    "{:?} is bound for players {:?}",
+                    conflict.key, conflict.players
+                );
+            }
+        }
+    }
+    if !screen.is_changed() && !bindings.is_changed() {
+        return;
+    }
+    for (MenuButtonId(id), children) in &button_query {
+        let Some(label) = id.as_deref().and_then(|id| screen.label(&bindings, id)) else {
+            continue;
+        };
+        if let Ok(mut text) = text_query.get_mut(children[0]) {
+            text.sections[0].value = label;
+        }
+    }
+    for entity in &status_query {
+        if let Ok(mut text) = text_query.get_mut(entity) {
+            text.sections[0].value = screen.status.clone();
+        }
+    }
+    let warning = conflict_warning(&bindings.keyboard_conflicts());
+    for entity in &warning_query {
+        if let Ok(mut text) = text_query.get_mut(entity) {
+            text.sections[0].value = warning.clone();
+        }
+    }
+}
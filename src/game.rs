@@ -1,12 +1,21 @@
+use std::f32::consts::PI;
+
 use bevy::prelude::Commands;
-use bevy::prelude::*;
+use bevy::{asset::LoadedFolder, prelude::*};
 // use bevy_inspector_egui::quick::WorldInspectorPlugin;
 use bevy_rapier2d::prelude::*;
 use leafwing_input_manager::prelude::*;
+use serde::Deserialize;
 
 // use bevy_egui::{egui, EguiContexts, EguiPlugin};
 use crate::{
+    ai::AiController,
+    arena::{spawn_arena, Arena, Arenas, CurrentArena, BALL_GROUP},
     calc,
+    interpolation::Interpolated,
+    match_setup::MatchConfig,
+    player::{mouse_input_map, Bindings, Controller, MouseControl, Player, RemoteInput},
+    rules::{MatchProgress, MatchResult},
     Acceleration,
     Action,
     Ball,
@@ -16,60 +25,235 @@ use crate::{
     RotAcceleration,
     Rotating,
     RotatingM,
+    RotationQueue,
+    RotationStep,
     RotationVelocity,
 };
 
-pub fn setup_game(mut commands: Commands) {
-    let default_map = InputMap::new([
-        (KeyCode::A, Action::Left),
-        (KeyCode::D, Action::Right),
-        (KeyCode::W, Action::Up),
-        (KeyCode::S, Action::Down),
-        (KeyCode::C, Action::RotateAntiClockwise),
-        (KeyCode::V, Action::RotateClockwise),
-    ]);
-    commands
-        .spawn(TransformBundle::from(Transform::from_xyz(-500.0, 0.0, 0.0)))
-        .insert(RigidBody::Fixed)
-        .insert(Collider::cuboid(0.0, 1000.0))
-        .insert(Restitution::coefficient(0.0));
-    commands
-        .spawn(TransformBundle::from(Transform::from_xyz(500.0, 0.0, 0.0)))
-        .insert(RigidBody::Fixed)
-        .insert(Collider::cuboid(0.0, 1000.0))
-        .insert(Restitution::coefficient(0.0));
-    commands
-        .spawn(TransformBundle::from(Transform::from_xyz(0.0, -250.0, 0.0)))
-        .insert(RigidBody::Fixed)
-        .insert(Collider::cuboid(1000.0, 0.0))
-        .insert(Restitution::coefficient(0.0));
-    commands
-        .spawn(TransformBundle::from(Transform::from_xyz(0.0, 250.0, 0.0)))
-        .insert(RigidBody::Fixed)
-        .insert(Collider::cuboid(1000.0, 0.0))
-        .insert(Restitution::coefficient(0.0));
+/// Gameplay and physics ticks per second, whatever the display's refresh rate.
+pub const TICK_RATE: f64 = 60.0;
+
+/// Which half of the arena something belongs to.
+#[derive(Component, Reflect, Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[reflect(Component)]
+pub enum Side {
+    #[default]
+    Left,
+    Right,
+}
+
+impl Side {
+    pub const fn opponent(self) -> Self {
+        match self {
+            Self::Left => Self::Right,
+            Self::Right => Self::Left,
+        }
+    }
+
+    /// -1 for the left half, 1 for the right half
+    pub const fn sign(self) -> f32 {
+        match self {
+            Self::Left => -1.0,
+            Self::Right => 1.0,
+        }
+    }
+}
+
+/// A goal sensor, defended by the given side.
+#[derive(Component)]
+pub struct Goal(pub Side);
+
+/// Where a paddle goes back to at the start of every round.
+#[derive(Component)]
+pub struct SpawnPoint(pub Vec2);
+
+/// Everything spawned by [`setup_game`], so it can be cleaned up afterwards.
+#[derive(Component)]
+pub struct GameEntity;
+
+#[derive(Component)]
+pub struct ScoreText;
+
+#[derive(Event, Debug)]
+pub struct GoalScored {
+    /// the side that gets the point
+    pub scorer: Side,
+}
+
+#[derive(Resource, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Score {
+    pub left: u32,
+    pub right: u32,
+}
+
+impl Score {
+    pub const fn get(&self, side: Side) -> u32 {
+        match side {
+            Side::Left => self.left,
+            Side::Right => self.right,
+        }
+    }
+
+    pub fn add(&mut self, side: Side) {
+        match side {
+            Side::Left => self.left += 1,
+            Side::Right => self.right += 1,
+        }
+    }
+}
+
+/// Counts down before the ball is served, and remembers who it's served to.
+#[derive(Resource, Clone)]
+pub struct Serve {
+    pub timer: Timer,
+    pub towards: Side,
+}
+
+impl Serve {
+    pub fn towards(side: Side) -> Self {
+        Self {
+            timer: Timer::from_seconds(SERVE_DELAY, TimerMode::Once),
+            towards: side,
+        }
+    }
+}
+
+pub const BALL_RADIUS: f32 = 15.0;
+pub const SERVE_DELAY: f32 = 1.0;
+
+/// Everything about how fast the ball goes, in one place.
+#[derive(Resource, Reflect, Clone, Copy, Debug)]
+#[reflect(Resource)]
+pub struct BallSpeed {
+    /// speed the ball is served at, and the slowest it'll ever go during a rally
+    pub serve: f32,
+    /// added to the speed every time a paddle hits the ball
+    pub increment: f32,
+    /// the ball never goes faster than this
+    pub max: f32,
+    /// how much of the paddle's velocity bends the ball's direction
+    pub angle_transfer: f32,
+    /// how much spin the ball picks up from a paddle sliding across it
+    pub spin_transfer: f32,
+    /// how hard spin curves the ball's path
+    pub curve: f32,
+    /// extra speed for hitting the ball mid-rotation, per radian/frame the paddle is turning
+    pub smash_speed: f32,
+    /// extra spin for hitting the ball mid-rotation, per radian/frame the paddle is turning
+    pub smash_spin: f32,
+}
+
+impl Default for BallSpeed {
+    fn default() -> Self {
+        Self {
+            serve: 300.0,
+            increment: 40.0,
+            max: 1400.0,
+            angle_transfer: 0.3,
+            spin_transfer: 0.01,
+            curve: 0.15,
+            smash_speed: 1500.0,
+            smash_spin: 15.0,
+        }
+    }
+}
+
+/// How fast the ball is going this rally. 0 before it's been served.
+#[derive(Component, Reflect, Default)]
+#[reflect(Component)]
+pub struct RallySpeed(pub f32);
+
+/// The paddles the ball was touching at the end of the last tick. Kept with
+/// the rest of the game state rather than left to Rapier's contact events, so
+/// a tick played again after a rollback sees the same hits.
+#[derive(Component, Default, Clone, Debug)]
+pub struct PaddleContacts(pub Vec<Entity>);
+
+pub fn setup_game(
+    mut commands: Commands,
+    config: Res<MatchConfig>,
+    bindings: Res<Bindings>,
+    server: Res<AssetServer>,
+    arenas: Res<Arenas>,
+    folders: Res<Assets<LoadedFolder>>,
+    arena_assets: Res<Assets<Arena>>,
+) {
+    let mut roster = config.roster(&bindings);
+    for conflict in roster.resolve_conflicts() {
+        warn!(
+            "{:?} is bound for players {:?}",
+            conflict.key, conflict.players
+        );
+    }
+    commands.insert_resource(config.rules());
+    commands.insert_resource(config.ball_speed());
+
+    let handle = arenas.get(&folders, &config.arena);
+    let arena = handle
+        .as_ref()
+        .and_then(|handle| arena_assets.get(handle))
+        .cloned()
+        .unwrap_or_else(|| {
+            warn!("arena {} isn't loaded, using the default", config.arena);
+            Arena::default()
+        });
+    spawn_arena(&mut commands, &arena, &server);
+    // the arena decides where everyone starts, if it says
+    for (player, spawn) in roster.0.iter_mut().zip(&arena.spawns) {
+        player.spawn = spawn.position;
+        player.side = spawn.side;
+    }
 
     commands
-        .spawn(TransformBundle::from(Transform::from_xyz(50.0, 0.0, 0.0)))
+        .spawn(TransformBundle::from(Transform::from_translation(
+            arena.ball_spawn.extend(0.0),
+        )))
         .insert(RigidBody::Dynamic)
         .insert(Ball)
-        .insert(Collider::ball(15.0))
+        .insert(GameEntity)
+        .insert(Collider::ball(BALL_RADIUS))
+        .insert(CollisionGroups::new(BALL_GROUP, Group::ALL))
         .insert(CollidingEntities::default())
         // add external imp
         .insert(ExternalImpulse::default())
+        .insert(RallySpeed(0.0))
+        .insert(PaddleContacts::default())
+        // fast balls would tunnel through thin walls otherwise
+        .insert(Ccd::enabled())
+        .insert(Interpolated::default())
         // .insert(ActiveHooks::MODIFY_SOLVER_CONTACTS)
         .insert(Restitution::coefficient(1.2))
-        .insert(Velocity {
-            linvel: Vec2::new(1.0, 2.0),
-            angvel: 0.4,
-        })
+        .insert(Velocity::zero())
         .insert(ActiveEvents::COLLISION_EVENTS)
-        .insert(ActiveCollisionTypes::all())
-        .insert(TransformBundle::from(Transform::from_xyz(400.0, 0.0, 0.0)));
+        .insert(ActiveCollisionTypes::all());
+    commands.insert_resource(Score::default());
+    commands.insert_resource(MatchProgress::default());
+    commands.insert_resource(roster.clone());
+    commands.insert_resource(CurrentArena { handle, arena });
+    commands.insert_resource(Serve::towards(Side::Right));
+
+    commands.spawn((
+        TextBundle::from_section(
+            "0 - 0",
+            TextStyle {
+                font: server.load("Blazma/Blazma-Regular.ttf"),
+                font_size: 40.0,
+                color: Color::rgb(0.9, 0.9, 0.9),
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            top: Val::Px(10.0),
+            left: Val::Percent(47.0),
+            ..default()
+        }),
+        ScoreText,
+        GameEntity,
+    ));
 
-    for _ in 0..1 {
+    for (i, player) in roster.0.iter().enumerate() {
         // let mut commands = world.get_resource_mut::<Commands>().unwrap();
-        commands
+        let paddle = commands
             .spawn(PaddleBundle {
                 flags: ActiveEvents::COLLISION_EVENTS,
                 active_collision_types: ActiveCollisionTypes::default(),
@@ -78,9 +262,11 @@ pub fn setup_game(mut commands: Commands) {
                 rot_acceleration: calc::ROT_ACCELERATION,
                 next_stop: NextStop(0.0),
                 rotating: Rotating(RotatingM::Neither),
+                rotation_queue: RotationQueue::default(),
+                rotation_step: config.modifiers.rotation,
                 sprite: SpriteBundle {
                     sprite: Sprite {
-                        color: Color::rgb(0.5, 0.5, 1.0),
+                        color: player.colour,
                         custom_size: Some(Vec2::new(30.0, 150.0)),
                         ..Default::default()
                     },
@@ -88,12 +274,12 @@ pub fn setup_game(mut commands: Commands) {
                 },
             })
             .insert(Paddle)
-            .insert(InputManagerBundle::<Action> {
-                // Stores "which actions are currently pressed"
-                action_state: ActionState::default(),
-                // Describes how to convert from player inputs into those actions
-                input_map: default_map.clone(),
-            })
+            .insert(Interpolated::default())
+            .insert(Player(i))
+            .insert(player.side)
+            .insert(SpawnPoint(player.spawn))
+            .insert(GameEntity)
+            .insert(ActionState::<Action>::default())
             .insert(RigidBody::Dynamic)
             .insert(Damping {
                 linear_damping: 1.7,
@@ -105,7 +291,27 @@ pub fn setup_game(mut commands: Commands) {
             })
             .insert(Collider::cuboid(15.0, 75.0))
             .insert(CollidingEntities::default())
-            .insert(TransformBundle::from(Transform::from_xyz(0.0, 0.0, 0.0)));
+            .insert(TransformBundle::from(paddle_transform(player.spawn)))
+            .id();
+        match &player.controller {
+            // Describes how to convert from player inputs into those actions
+            Controller::Human(input_map) => {
+                commands.entity(paddle).insert(input_map.clone());
+            }
+            // Steers towards the cursor
+            Controller::Mouse => {
+                commands
+                    .entity(paddle)
+                    .insert(mouse_input_map())
+                    .insert(MouseControl);
+            }
+            // Presses the actions itself
+            Controller::Computer(difficulty) => {
+                commands
+                    .entity(paddle)
+                    .insert(AiController::new(*difficulty, i as u32));
+            }
+        }
         // world.resource_scope(|_, mut table: Mut<Table>| {
         // table.paddles[i].push(paddle);
         // });
@@ -114,51 +320,290 @@ pub fn setup_game(mut commands: Commands) {
     //     paddles: paddles,
     // })));
 }
+/// Despawns everything [`setup_game`] made, ready for the next match.
+pub fn teardown_game(mut commands: Commands, query: Query<Entity, With<GameEntity>>) {
+    despawn_game(&mut commands, &query);
+}
+
+pub fn despawn_game(commands: &mut Commands, query: &Query<Entity, With<GameEntity>>) {
+    for entity in query {
+        commands.entity(entity).despawn_recursive();
+    }
+    commands.remove_resource::<Score>();
+    commands.remove_resource::<Serve>();
+    commands.remove_resource::<MatchProgress>();
+    commands.remove_resource::<MatchResult>();
+    commands.remove_resource::<CurrentArena>();
+}
+
+pub fn freeze_physics(mut rapier_config: ResMut<RapierConfiguration>) {
+    rapier_config.physics_pipeline_active = false;
+}
+
+pub fn unfreeze_physics(mut rapier_config: ResMut<RapierConfiguration>) {
+    rapier_config.physics_pipeline_active = true;
+}
+
+/// A paddle sitting at `position`, facing the way `NextStop(0.0)` expects.
+fn paddle_transform(position: Vec2) -> Transform {
+    Transform::from_translation(position.extend(0.0)).with_rotation(Quat::from_rotation_z(PI))
+}
+
+#[allow(clippy::type_complexity)]
 pub fn movement(
+    time: Res<Time>,
     mut query: Query<
         (
             &ActionState<Action>,
             &Acceleration,
             &mut NextStop,
+            &mut RotationVelocity,
             &mut Rotating,
+            &mut RotationQueue,
             &mut Velocity,
             &mut Transform,
             &RotAcceleration,
+            &RotationStep,
         ),
         With<Paddle>,
     >,
 ) {
-    for (action_state, acceleration, next_stop, rotating, vel, transform, rot_acceleration) in
-        &mut query
+    for (
+        action_state,
+        acceleration,
+        next_stop,
+        rotation_velocity,
+        rotating,
+        queue,
+        vel,
+        transform,
+        rot_acceleration,
+        rotation_step,
+    ) in &mut query
     {
         calc::paddle_sim(
+            time.delta_seconds(),
             transform,
             rotating,
+            queue,
             next_stop,
+            rotation_velocity,
             action_state,
             vel,
             acceleration,
             rot_acceleration,
+            rotation_step,
         );
     }
 }
 
+/// Feeds rotate presses into each paddle's [`RotationQueue`] for
+/// [`movement`] to pick up on the next tick. Online paddles get theirs from
+/// the network instead.
+#[allow(clippy::type_complexity)]
+pub fn queue_rotations(
+    mut query: Query<
+        (&ActionState<Action>, &mut RotationQueue),
+        (With<Paddle>, Without<RemoteInput>),
+    >,
+) {
+    for (action_state, mut queue) in &mut query {
+        calc::queue_rotations(action_state, &mut queue);
+    }
+}
+
+/// Speeds the ball up once per distinct paddle hit, and hands over some of
+/// the paddle's movement as angle and spin.
+#[allow(clippy::type_complexity)]
 pub fn ball_collision_detection(
-    // commands: Commands,
-    mut ball_query: Query<(&mut Velocity, Entity), With<Ball>>,
-    colliding_entities_query: Query<&CollidingEntities, With<Paddle>>,
+    context: Res<RapierContext>,
+    ball_speed: Res<BallSpeed>,
+    mut ball_query: Query<
+        (
+            Entity,
+            &Transform,
+            &mut Velocity,
+            &mut RallySpeed,
+            &mut PaddleContacts,
+        ),
+        (With<Ball>, Without<Paddle>),
+    >,
+    paddle_query: Query<
+        (Entity, &Transform, &Velocity, &Rotating, &RotationVelocity),
+        With<Paddle>,
+    >,
+) {
+    for (ball, ball_transform, mut vel, mut rally, mut contacts) in &mut ball_query {
+        let touching: Vec<Entity> = paddle_query
+            .iter()
+            .map(|(paddle, ..)| paddle)
+            .filter(|paddle| {
+                context
+                    .contact_pair(ball, *paddle)
+                    .is_some_and(|pair| pair.has_any_active_contacts())
+            })
+            .collect();
+        // only paddles that weren't already touching it hit it
+        let hits = touching
+            .iter()
+            .filter(|paddle| !contacts.0.contains(paddle));
+        for (_, paddle_transform, paddle_vel, rotating, rotation_velocity) in
+            paddle_query.iter_many(hits)
+        {
+            // caught mid-rotation: the faster the paddle is turning, the harder the hit
+            let smash = if rotating.0 == RotatingM::Neither {
+                0.0
+            } else {
+                rotation_velocity.0
+            };
+
+            // make the ball go faster
+            rally.0 = (rally.0 + ball_speed.increment + smash.abs() * ball_speed.smash_speed)
+                .clamp(ball_speed.serve, ball_speed.max);
+
+            // the face the ball bounced off, pointing at the ball
+            let to_ball = (ball_transform.translation - paddle_transform.translation).truncate();
+            let mut normal = (paddle_transform.rotation * Vec3::X).truncate();
+            if normal.dot(to_ball) < 0.0 {
+                normal = -normal;
+            }
+
+            let mut direction = vel.linvel.normalize_or_zero();
+            if direction == Vec2::ZERO {
+                direction = normal;
+            }
+            direction += paddle_vel.linvel * ball_speed.angle_transfer / rally.0;
+            // never send it back into the paddle
+            if direction.dot(normal) < 0.1 {
+                direction = direction.reject_from(normal) + normal * 0.1;
+            }
+            vel.linvel = direction.normalize() * rally.0;
+
+            // sliding along the face puts spin on the ball
+            let slide = paddle_vel.linvel.dot(normal.perp());
+            vel.angvel -= slide * ball_speed.spin_transfer;
+            // and a smash curves it the same way the paddle was turning
+            vel.angvel += smash * ball_speed.smash_spin;
+        }
+        contacts.0 = touching;
+    }
+}
+
+/// Keeps the ball moving at its rally speed no matter how it bounced, and
+/// bends its path according to its spin.
+pub fn ball_speed_control(
+    time: Res<Time>,
+    ball_speed: Res<BallSpeed>,
+    mut ball_query: Query<(&mut Velocity, &RallySpeed), With<Ball>>,
+) {
+    for (mut vel, rally) in &mut ball_query {
+        // still waiting to be served
+        if rally.0 <= 0.0 || vel.linvel == Vec2::ZERO {
+            continue;
+        }
+        let bend = Vec2::from_angle(vel.angvel * ball_speed.curve * time.delta_seconds());
+        vel.linvel = bend.rotate(vel.linvel).normalize() * rally.0;
+    }
+}
+
+/// Turns the ball being in a goal sensor into a [`GoalScored`] event.
+/// [`reset_round`] takes it straight back out, so each goal counts once.
+pub fn goal_detection(
+    context: Res<RapierContext>,
+    ball_query: Query<Entity, With<Ball>>,
+    goal_query: Query<(Entity, &Goal)>,
+    mut goal_scored: EventWriter<GoalScored>,
 ) {
-    for colliding_entities in colliding_entities_query.iter() {
-        for (mut vel, ball_ent) in &mut ball_query {
-            if colliding_entities.contains(ball_ent) {
-                println!("collision detected");
-                // commands.despawn(ball_ent);
-                // commands.spawn((Ball, Transform::from_translation(Vec3::new(400.0, 300.0, 0.0))));
-                // make the ball go faster
-                // imp.impulse += Vec2::new(100.0, 100.0)
-                vel.linvel *= 2.5;
+    for ball in &ball_query {
+        for (goal, Goal(defender)) in &goal_query {
+            if context.intersection_pair(ball, goal) == Some(true) {
+                goal_scored.send(GoalScored {
+                    scorer: defender.opponent(),
+                });
             }
-            // println!("vel: {:?}", vel.linvel);
         }
     }
 }
+
+pub fn update_score(
+    mut goal_scored: EventReader<GoalScored>,
+    mut score: ResMut<Score>,
+    mut text_query: Query<&mut Text, With<ScoreText>>,
+) {
+    for GoalScored { scorer } in goal_scored.read() {
+        score.add(*scorer);
+        info!("goal for {scorer:?}, score is now {score:?}");
+    }
+    if score.is_changed() {
+        for mut text in &mut text_query {
+            text.sections[0].value = format!("{} - {}", score.left, score.right);
+        }
+    }
+}
+
+/// Puts everything back where it started after a goal, and serves the ball to
+/// the side that just conceded.
+#[allow(clippy::type_complexity)]
+pub fn reset_round(
+    mut commands: Commands,
+    arena: Res<CurrentArena>,
+    mut goal_scored: EventReader<GoalScored>,
+    mut ball_query: Query<
+        (&mut Transform, &mut Velocity, &mut RallySpeed),
+        (With<Ball>, Without<Paddle>),
+    >,
+    mut paddle_query: Query<
+        (
+            &SpawnPoint,
+            &mut Transform,
+            &mut Velocity,
+            &mut NextStop,
+            &mut Rotating,
+            &mut RotationQueue,
+            &mut RotationVelocity,
+        ),
+        With<Paddle>,
+    >,
+) {
+    let Some(GoalScored { scorer }) = goal_scored.read().last() else {
+        return;
+    };
+    for (mut transform, mut vel, mut rally) in &mut ball_query {
+        *transform = Transform::from_translation(arena.arena.ball_spawn.extend(0.0));
+        *vel = Velocity::zero();
+        rally.0 = 0.0;
+    }
+    for (spawn, mut transform, mut vel, mut next_stop, mut rotating, mut queue, mut spin) in
+        &mut paddle_query
+    {
+        *transform = paddle_transform(spawn.0);
+        *vel = Velocity::zero();
+        next_stop.0 = 0.0;
+        rotating.0 = RotatingM::Neither;
+        queue.0.clear();
+        spin.0 = 0.0;
+    }
+    commands.insert_resource(Serve::towards(scorer.opponent()));
+}
+
+/// Launches the ball once the serve countdown runs out, unless a paddle has
+/// already hit it into play.
+pub fn serve_ball(
+    time: Res<Time>,
+    ball_speed: Res<BallSpeed>,
+    mut serve: ResMut<Serve>,
+    mut ball_query: Query<(&mut Velocity, &mut RallySpeed), With<Ball>>,
+) {
+    if !serve.timer.tick(time.delta()).just_finished() {
+        return;
+    }
+    for (mut vel, mut rally) in &mut ball_query {
+        // only a paddle hit gives the ball a rally speed before the serve
+        if rally.0 > 0.0 {
+            continue;
+        }
+        rally.0 = ball_speed.serve;
+        vel.linvel = Vec2::new(serve.towards.sign(), 0.3).normalize() * rally.0;
+        vel.angvel = 0.0;
+    }
+}
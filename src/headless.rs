@@ -0,0 +1,262 @@
+//! Runs matches without a window, renderer or menus, one fixed tick per
+//! update, so gameplay can be tested from code.
+
+use std::time::Duration;
+
+use bevy::{asset::LoadedFolder, prelude::*, time::TimeUpdateStrategy};
+use bevy_rapier2d::prelude::{RapierConfiguration, TimestepMode, Velocity};
+use leafwing_input_manager::{plugin::InputManagerSystem, prelude::*};
+
+use crate::{
+    arena::Arenas,
+    match_setup::MatchConfig,
+    player::Player,
+    Action,
+    AppState,
+    Ball,
+    GamePlugin,
+    Paddle,
+};
+
+/// A gameplay-only app that plays `config` when [`start_match`] is called.
+/// Every [`App::update`] advances exactly one fixed tick.
+pub fn headless_app(config: MatchConfig) -> App {
+    let mut app = App::new();
+    app.add_plugins((
+        MinimalPlugins,
+        AssetPlugin::default(),
+        TransformPlugin,
+        HierarchyPlugin,
+        bevy::input::InputPlugin,
+    ))
+    // spawned by the match, even though nothing draws them
+    .init_asset::<Font>()
+    .init_asset::<Image>()
+    .add_plugins(GamePlugin)
+    // no arena files, so every match is played in the default arena
+    .insert_resource(Arenas(Handle::<LoadedFolder>::default()))
+    .insert_resource(config);
+    let tick = app.world.resource::<Time<Fixed>>().timestep();
+    app.insert_resource(TimeUpdateStrategy::ManualDuration(tick))
+        .add_systems(
+            PreUpdate,
+            press_held_actions.in_set(InputManagerSystem::ManualControl),
+        );
+    // run startup
+    app.update();
+    app
+}
+
+/// Plays `hz` fixed ticks a second instead of [`TICK_RATE`](crate::game::TICK_RATE),
+/// still one per update.
+pub fn set_tick_rate(app: &mut App, hz: f64) {
+    let tick = Duration::from_secs_f64(1.0 / hz);
+    app.world.resource_mut::<Time<Fixed>>().set_timestep(tick);
+    app.insert_resource(TimeUpdateStrategy::ManualDuration(tick));
+    app.world
+        .resource_mut::<RapierConfiguration>()
+        .timestep_mode = TimestepMode::Fixed {
+        dt: tick.as_secs_f32(),
+        substeps: 1,
+    };
+}
+
+pub fn start_match(app: &mut App) {
+    app.world
+        .resource_mut::<NextState<AppState>>()
+        .set(AppState::InGame);
+    app.update();
+}
+
+pub fn step(app: &mut App, ticks: usize) {
+    for _ in 0..ticks {
+        app.update();
+    }
+}
+
+fn paddle(app: &mut App, player: usize) -> Entity {
+    let mut query = app
+        .world
+        .query_filtered::<(Entity, &Player), With<Paddle>>();
+    query
+        .iter(&app.world)
+        .find(|(_, p)| p.0 == player)
+        .map(|(entity, _)| entity)
+        .expect("no paddle for that player")
+}
+
+/// Actions a test is holding down for a paddle, in place of its bindings.
+#[derive(Component, Default)]
+struct HeldActions(Vec<Action>);
+
+/// Presses every [`HeldActions`] action and releases the rest, after the
+/// input manager has had its turn, so presses still show up as just pressed.
+fn press_held_actions(mut query: Query<(&HeldActions, &mut ActionState<Action>)>) {
+    for (held, mut action_state) in &mut query {
+        for action in Action::variants() {
+            if held.0.contains(&action) {
+                action_state.press(action);
+            } else if action_state.pressed(action) {
+                action_state.release(action);
+            }
+        }
+    }
+}
+
+/// Holds `action` down for `player` from the next update until [`release`]
+/// is called. Their bindings are dropped so real input can't interfere.
+pub fn press(app: &mut App, player: usize, action: Action) {
+    let paddle = paddle(app, player);
+    let mut entity = app.world.entity_mut(paddle);
+    entity.remove::<InputMap<Action>>();
+    if !entity.contains::<HeldActions>() {
+        entity.insert(HeldActions::default());
+    }
+    entity.get_mut::<HeldActions>().unwrap().0.push(action);
+}
+
+pub fn release(app: &mut App, player: usize, action: Action) {
+    let paddle = paddle(app, player);
+    if let Some(mut held) = app.world.get_mut::<HeldActions>(paddle) {
+        held.0.retain(|held| *held != action);
+    }
+}
+
+pub fn paddle_transform(app: &mut App, player: usize) -> Transform {
+    let paddle = paddle(app, player);
+    *app.world.get::<Transform>(paddle).unwrap()
+}
+
+pub fn ball(app: &mut App) -> (Transform, Velocity) {
+    let mut query = app
+        .world
+        .query_filtered::<(&Transform, &Velocity), With<Ball>>();
+    let (transform, velocity) = query.single(&app.world);
+    (*transform, *velocity)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::{RallySpeed, Score, Side, SERVE_DELAY, TICK_RATE};
+
+    /// enough ticks for the serve countdown to run out
+    const SERVE_TICKS: usize = (SERVE_DELAY as f64 * TICK_RATE) as usize + 2;
+
+    fn started() -> App {
+        let mut app = headless_app(MatchConfig::default());
+        start_match(&mut app);
+        app
+    }
+
+    #[test]
+    fn match_spawns_paddles_and_ball() {
+        let mut app = started();
+        let paddles = app
+            .world
+            .query_filtered::<(), With<Paddle>>()
+            .iter(&app.world)
+            .count();
+        assert_eq!(paddles, 2);
+        let (transform, velocity) = ball(&mut app);
+        assert_eq!(transform.translation.truncate(), Vec2::ZERO);
+        assert_eq!(velocity.linvel, Vec2::ZERO);
+        assert_eq!(*app.world.resource::<Score>(), Score::default());
+    }
+
+    #[test]
+    fn ball_is_served_after_the_countdown() {
+        let mut app = started();
+        step(&mut app, SERVE_TICKS - 5);
+        assert_eq!(ball(&mut app).1.linvel, Vec2::ZERO);
+        step(&mut app, 5);
+        let (transform, velocity) = ball(&mut app);
+        assert!(velocity.linvel.x > 0.0, "served towards the right");
+        assert!(transform.translation.x > 0.0);
+    }
+
+    #[test]
+    fn ball_hit_during_the_countdown_isnt_served_again() {
+        let mut app = started();
+        // as if a paddle had knocked it straight up
+        let mut query = app
+            .world
+            .query_filtered::<(&mut Velocity, &mut RallySpeed), With<Ball>>();
+        let (mut velocity, mut rally) = query.single_mut(&mut app.world);
+        velocity.linvel = Vec2::new(0.0, 300.0);
+        rally.0 = 300.0;
+        step(&mut app, SERVE_TICKS);
+        let velocity = ball(&mut app).1.linvel;
+        assert!(velocity.x.abs() < 1.0, "served anyway: {velocity}");
+    }
+
+    #[test]
+    fn holding_up_moves_the_paddle_up() {
+        let mut app = started();
+        let start = paddle_transform(&mut app, 0).translation;
+        press(&mut app, 0, Action::Up);
+        step(&mut app, 20);
+        let moved = paddle_transform(&mut app, 0).translation;
+        assert!(moved.y > start.y + 10.0, "{start} -> {moved}");
+        assert!((moved.x - start.x).abs() < 1.0);
+        // and the other paddle stays put
+        release(&mut app, 0, Action::Up);
+        let other = paddle_transform(&mut app, 1).translation;
+        step(&mut app, 5);
+        assert_eq!(paddle_transform(&mut app, 1).translation, other);
+    }
+
+    #[test]
+    fn paddles_cant_leave_through_the_goal() {
+        let mut app = started();
+        press(&mut app, 0, Action::Left);
+        step(&mut app, 4 * TICK_RATE as usize);
+        let x = paddle_transform(&mut app, 0).translation.x;
+        // the left goal line is at -500
+        assert!(x > -500.0, "paddle got to {x}");
+    }
+
+    #[test]
+    fn rotate_press_turns_the_paddle_a_step() {
+        let mut app = started();
+        let start = paddle_transform(&mut app, 0).rotation;
+        press(&mut app, 0, Action::RotateClockwise);
+        step(&mut app, 1);
+        release(&mut app, 0, Action::RotateClockwise);
+        step(&mut app, 2 * TICK_RATE as usize);
+        let angle = paddle_transform(&mut app, 0).rotation.angle_between(start);
+        assert!(
+            (angle.to_degrees() - 90.0).abs() < 1.0,
+            "turned {} degrees",
+            angle.to_degrees()
+        );
+    }
+
+    #[test]
+    fn unreturned_serve_scores_for_the_server() {
+        let mut app = started();
+        step(&mut app, SERVE_TICKS + 4 * TICK_RATE as usize);
+        // served right at an angle that misses the still paddle
+        let score = *app.world.resource::<Score>();
+        assert_eq!(score.get(Side::Left), 1, "{score:?}");
+        assert_eq!(score.get(Side::Right), 0);
+    }
+
+    #[test]
+    fn simulation_is_deterministic() {
+        let run = || {
+            let mut app = started();
+            press(&mut app, 1, Action::Down);
+            press(&mut app, 0, Action::RotateAntiClockwise);
+            step(&mut app, 200);
+            let (ball, velocity) = ball(&mut app);
+            (
+                ball,
+                velocity.linvel,
+                paddle_transform(&mut app, 0),
+                paddle_transform(&mut app, 1),
+            )
+        };
+        assert_eq!(run(), run());
+    }
+}
@@ -0,0 +1,41 @@
+use bevy::prelude::*;
+
+/// Smooths out a physics body drawn between fixed ticks. Holds where the body
+/// was at the end of the previous tick; its `Transform` is where it is now.
+#[derive(Component, Default)]
+pub struct Interpolated {
+    previous: Option<Transform>,
+}
+
+/// Puts the real physics pose back before a tick, since [`interpolate`] drew
+/// something in between, and remembers it to interpolate from.
+///
+/// Rapier only notices a teleport if the `GlobalTransform` differs from what
+/// it last wrote, so this has to run before its sync.
+pub fn restore_physics_transforms(
+    mut query: Query<(&Transform, &mut GlobalTransform, &mut Interpolated), Without<Parent>>,
+) {
+    for (transform, mut global, mut interpolated) in &mut query {
+        *global = GlobalTransform::from(*transform);
+        interpolated.previous = Some(*transform);
+    }
+}
+
+/// Draws each [`Interpolated`] body part of the way from its previous pose
+/// to its current one, by how far into the next fixed tick we are.
+pub fn interpolate(
+    time: Res<Time<Fixed>>,
+    mut query: Query<(&Transform, &mut GlobalTransform, &Interpolated), Without<Parent>>,
+) {
+    let alpha = time.overstep_percentage();
+    for (transform, mut global, interpolated) in &mut query {
+        let Some(previous) = interpolated.previous else {
+            continue;
+        };
+        *global = GlobalTransform::from(Transform {
+            translation: previous.translation.lerp(transform.translation, alpha),
+            rotation: previous.rotation.slerp(transform.rotation, alpha),
+            scale: transform.scale,
+        });
+    }
+}
@@ -0,0 +1,657 @@
+//! ppan as a library: add [`GamePlugin`] to run matches, and [`InputPlugin`]
+//! and [`MenuPlugin`] for the rest of the game.
+
+use std::{collections::VecDeque, time::Duration};
+
+use bevy::{ecs::schedule::ScheduleLabel, prelude::*, transform::TransformSystem};
+use bevy_asset::{AssetServer, Handle};
+use bevy_rapier2d::prelude::*;
+use bevy_tweening::{
+    lens::UiBackgroundColorLens,
+    Animator,
+    EaseFunction,
+    RepeatCount,
+    RepeatStrategy,
+    Tween,
+    TweeningPlugin,
+};
+use leafwing_input_manager::prelude::*;
+use serde::{Deserialize, Serialize};
+pub mod ai;
+pub mod arena;
+pub mod calc;
+pub mod checksum;
+pub mod client;
+pub mod controls;
+pub mod game;
+pub mod headless;
+pub mod interpolation;
+pub mod match_setup;
+pub mod menu_nav;
+pub mod net;
+pub mod pause;
+pub mod player;
+pub mod prompts;
+pub mod rollback;
+pub mod rules;
+pub mod server;
+
+use ai::{ai_control, Difficulty};
+use arena::{load_arenas, reload_arena, Arena, ArenaLoader, Arenas};
+use client::ServerConnection;
+use controls::{controls_menu, controls_update};
+#[cfg(feature = "discord")]
+use discord_game_sdk::Discord;
+use game::{
+    ball_collision_detection,
+    ball_speed_control,
+    freeze_physics,
+    goal_detection,
+    movement,
+    queue_rotations,
+    reset_round,
+    serve_ball,
+    setup_game,
+    teardown_game,
+    unfreeze_physics,
+    update_score,
+    BallSpeed,
+    GoalScored,
+    Score,
+    TICK_RATE,
+};
+use interpolation::{interpolate, restore_physics_transforms};
+use leafwing_input_manager::Actionlike;
+use match_setup::{setup_menu, setup_update, MatchConfig};
+use menu_nav::{
+    build_focus_graph,
+    highlight_focus,
+    menu_input_map,
+    menu_navigation,
+    FocusGraph,
+    MenuAction,
+    MenuFocus,
+};
+use pause::{pause_input, pause_menu, pause_update};
+use player::{assign_gamepads, load_bindings, mouse_control, save_bindings, Bindings};
+use prompts::{detect_input_device, update_prompt_glyphs, ActiveDevices};
+use rollback::RollbackSession;
+use rules::{
+    evaluate_rules,
+    match_clock,
+    match_over_menu,
+    match_over_update,
+    spawn_clock,
+    MatchRules,
+};
+#[derive(Actionlike, PartialEq, Eq, Clone, Copy, Hash, Debug, Reflect, Serialize, Deserialize)]
+pub enum Action {
+    Left,
+    Right,
+    Up,
+    Down,
+    RotateClockwise,
+    RotateAntiClockwise,
+    /// analog movement, for sticks
+    Move,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Default, States)]
+pub enum AppState {
+    #[default]
+    MainMenu,
+    Setup,
+    InGame,
+    Paused,
+    MatchOver,
+    Controls,
+}
+/// One fixed tick of a match. `FixedUpdate` runs it once per tick, unless a
+/// [`RollbackSession`] is running the ticks itself, or a server is running
+/// them for a [`ServerConnection`].
+#[derive(ScheduleLabel, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct GameTick;
+
+#[cfg(feature = "discord")]
+struct DiscordState<'a>(Discord<'a, ()>);
+
+#[derive(PartialEq, Eq, Reflect, Default, Clone, Copy, Debug)]
+pub enum RotatingM {
+    Clockwise,
+    AntiClockwise,
+    #[default]
+    Neither,
+}
+
+#[derive(Component)]
+pub struct Paddle;
+#[derive(Component)]
+pub struct Ball;
+
+#[derive(Component)]
+pub struct TopLevelNode;
+
+/// How far the paddle turned this frame, in radians. Positive is anticlockwise.
+#[derive(Component)]
+pub struct RotationVelocity(pub f32);
+
+#[derive(Component)]
+pub struct Acceleration(pub f32);
+#[derive(Component)]
+pub struct RotAcceleration(pub f32);
+
+#[derive(Component, Reflect, Default)]
+#[reflect(Component)]
+pub struct NextStop(pub f32);
+
+#[derive(Reflect, Default)]
+#[reflect(Component)]
+#[derive(Component)]
+pub struct Rotating(pub RotatingM);
+
+/// How far a paddle turns for each rotate press.
+#[derive(Component, Reflect, Clone, Copy, PartialEq, Debug)]
+#[reflect(Component)]
+pub enum RotationStep {
+    /// turns this many degrees, then stops
+    Step(f32),
+    /// spins for as long as rotate is held
+    Free,
+}
+
+impl Default for RotationStep {
+    fn default() -> Self {
+        Self::Step(90.0)
+    }
+}
+
+/// Rotate presses that haven't been turned into a rotation step yet, so
+/// quick double taps make two steps. [`RotationStep::Free`] turns while the
+/// button is held instead, and doesn't use the queue.
+#[derive(Component, Default)]
+pub struct RotationQueue(pub VecDeque<QueuedRotation>);
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct QueuedRotation {
+    pub clockwise: bool,
+    /// seconds since it was pressed
+    pub age: f32,
+}
+
+#[derive(Bundle)]
+pub struct PaddleBundle {
+    pub flags: ActiveEvents,
+    pub active_collision_types: ActiveCollisionTypes,
+    pub rotation_velocity: RotationVelocity,
+    pub acceleration: Acceleration,
+    pub rot_acceleration: RotAcceleration,
+    pub next_stop: NextStop,
+    pub rotating: Rotating,
+    pub rotation_queue: RotationQueue,
+    pub rotation_step: RotationStep,
+    pub sprite: SpriteBundle,
+}
+#[derive(Component)]
+pub struct MenuButtonId(pub Option<String>);
+
+#[derive(Event)]
+pub struct MenuButtonPressed(pub String);
+
+#[derive(Bundle)]
+pub struct MenuButtonBundle {
+    pub node: NodeBundle,
+    pub mbid: MenuButtonId,
+}
+
+/// Runs matches: physics, paddle input and the game rules, with no menus or
+/// rendering of its own. Enough on its own for headless simulations.
+pub struct GamePlugin;
+
+impl Plugin for GamePlugin {
+    fn build(&self, app: &mut App) {
+        add_gameplay(app);
+    }
+}
+
+/// The main menu, match setup, pause, match over and controls screens, and
+/// getting around them with the mouse, keyboard or a gamepad.
+pub struct MenuPlugin;
+
+impl Plugin for MenuPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(TweeningPlugin);
+
+        // events
+
+        app.add_event::<MenuButtonPressed>();
+
+        // resources
+
+        app.init_resource::<MenuFocus>()
+            .init_resource::<FocusGraph>();
+
+        // misc systems
+        app.add_systems(Startup, setup).add_systems(
+            Update,
+            (
+                input_system,
+                build_focus_graph,
+                menu_navigation,
+                highlight_focus,
+            )
+                .chain()
+                .before(controls_update),
+        );
+
+        // pause systems
+        app.add_systems(
+            Update,
+            pause_input.run_if(in_state(AppState::InGame).or_else(in_state(AppState::Paused))),
+        )
+        .add_systems(OnEnter(AppState::Paused), pause_menu)
+        .add_systems(Update, pause_update.run_if(in_state(AppState::Paused)))
+        .add_systems(OnExit(AppState::Paused), despawn_top_level_nodes);
+
+        // match over systems
+        app.add_systems(OnEnter(AppState::MatchOver), match_over_menu)
+            .add_systems(
+                Update,
+                match_over_update.run_if(in_state(AppState::MatchOver)),
+            )
+            .add_systems(OnExit(AppState::MatchOver), despawn_top_level_nodes);
+
+        // menu systems
+        app.add_systems(OnEnter(AppState::MainMenu), menu_menu)
+            .add_systems(Update, menu_update.run_if(in_state(AppState::MainMenu)))
+            // exit menu
+            .add_systems(OnExit(AppState::MainMenu), despawn_top_level_nodes);
+        // setup systems
+        app.add_systems(OnEnter(AppState::Setup), setup_menu)
+            .add_systems(Update, setup_update.run_if(in_state(AppState::Setup)))
+            // exit menu
+            .add_systems(OnExit(AppState::Setup), despawn_top_level_nodes);
+        // controls systems
+        app.add_systems(OnEnter(AppState::Controls), (freeze_physics, controls_menu))
+            .add_systems(Update, controls_update.run_if(in_state(AppState::Controls)))
+            .add_systems(
+                OnExit(AppState::Controls),
+                (despawn_top_level_nodes, save_bindings, unfreeze_physics),
+            );
+    }
+}
+
+/// The player's saved bindings, menu controls, and working out which device
+/// each player is on for button prompts.
+pub struct InputPlugin;
+
+impl Plugin for InputPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(InputManagerPlugin::<MenuAction>::default())
+            .init_resource::<ActiveDevices>()
+            .init_resource::<ActionState<MenuAction>>()
+            .insert_resource(menu_input_map())
+            .add_systems(Startup, load_bindings)
+            .add_systems(Update, (detect_input_device, update_prompt_glyphs).chain());
+    }
+}
+
+/// Shows what the player's up to on their Discord profile.
+#[cfg(feature = "discord")]
+pub struct DiscordPlugin;
+
+#[cfg(feature = "discord")]
+impl Plugin for DiscordPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Startup, setup_discord)
+            .add_systems(Update, discord_update);
+    }
+}
+
+fn add_gameplay(app: &mut App) {
+    app.add_plugins(InputManagerPlugin::<Action>::default())
+        .add_plugins(
+            RapierPhysicsPlugin::<NoUserData>::pixels_per_meter(50.0).in_schedule(GameTick),
+        )
+        .add_state::<AppState>();
+
+    // events
+
+    app.add_event::<GoalScored>();
+
+    // resources
+
+    app.insert_resource(Time::<Fixed>::from_hz(TICK_RATE));
+    app.init_resource::<MatchConfig>()
+        .init_resource::<BallSpeed>()
+        .init_resource::<MatchRules>()
+        .init_resource::<Bindings>()
+        .register_type::<BallSpeed>()
+        .register_type::<MatchRules>();
+
+    // assets
+
+    app.init_asset::<Arena>().init_asset_loader::<ArenaLoader>();
+
+    // the headless app brings its own arenas
+    app.add_systems(
+        Startup,
+        (
+            setup_physics,
+            load_arenas.run_if(not(resource_exists::<Arenas>())),
+        ),
+    );
+
+    // game systems
+    app.add_systems(
+        OnEnter(AppState::InGame),
+        // coming back from the pause menu shouldn't set everything up again
+        (setup_game, spawn_clock)
+            .chain()
+            .run_if(not(resource_exists::<Score>())),
+    )
+    // input is read once a frame...
+    .add_systems(
+        Update,
+        (assign_gamepads, mouse_control, ai_control, queue_rotations)
+            .chain()
+            .run_if(in_state(AppState::InGame)),
+    )
+    // ...and the game runs on fixed ticks, so it plays the same at any frame rate
+    .add_systems(
+        FixedUpdate,
+        run_game_tick.run_if(
+            not(resource_exists::<RollbackSession>())
+                .and_then(not(resource_exists::<ServerConnection>())),
+        ),
+    )
+    .add_systems(
+        GameTick,
+        // even when paused, so paddles don't jitter between two ticks
+        restore_physics_transforms.before(PhysicsSet::SyncBackend),
+    )
+    .add_systems(
+        GameTick,
+        movement
+            .after(restore_physics_transforms)
+            .before(PhysicsSet::SyncBackend)
+            .run_if(in_state(AppState::InGame)),
+    )
+    .add_systems(
+        GameTick,
+        (ball_collision_detection, ball_speed_control)
+            .chain()
+            .after(PhysicsSet::Writeback)
+            .run_if(in_state(AppState::InGame)),
+    )
+    .add_systems(
+        GameTick,
+        (
+            goal_detection,
+            update_score,
+            evaluate_rules,
+            match_clock,
+            reset_round,
+            serve_ball,
+        )
+            .chain()
+            .after(ball_speed_control)
+            .run_if(in_state(AppState::InGame)),
+    )
+    .add_systems(
+        PostUpdate,
+        interpolate.after(TransformSystem::TransformPropagate),
+    );
+
+    // hot reloading only applies during a match
+    app.add_systems(
+        Update,
+        reload_arena.run_if(in_state(AppState::InGame).or_else(in_state(AppState::Paused))),
+    );
+
+    // the match stands still while paused or over
+    app.add_systems(OnEnter(AppState::Paused), freeze_physics)
+        .add_systems(OnExit(AppState::Paused), unfreeze_physics)
+        .add_systems(OnEnter(AppState::MatchOver), freeze_physics)
+        .add_systems(
+            OnExit(AppState::MatchOver),
+            (teardown_game, unfreeze_physics),
+        );
+}
+
+fn run_game_tick(world: &mut World) {
+    world.run_schedule(GameTick);
+}
+
+fn despawn_top_level_nodes(mut commands: Commands, query: Query<(Entity, With<TopLevelNode>)>) {
+    for entity in query.iter() {
+        // Remove the entity if it has MenuButtonId and Button components
+        commands.entity(entity.0).despawn_recursive();
+    }
+}
+
+const NORMAL_BUTTON_COLOR: Color = Color::rgb(0.15, 0.15, 0.15);
+const HOVERED_BUTTON_COLOR: Color = Color::rgb(0.25, 0.25, 0.25);
+
+fn setup_physics(mut rapier_config: ResMut<RapierConfiguration>) {
+    rapier_config.gravity = Vec2::new(0.0, 0.0);
+    // one physics step per gameplay tick
+    rapier_config.timestep_mode = TimestepMode::Fixed {
+        dt: 1.0 / TICK_RATE as f32,
+        substeps: 1,
+    };
+}
+
+fn setup(mut commands: Commands, server: Res<AssetServer>) {
+    commands.spawn(Camera2dBundle::default());
+    // server.().unwrap();
+
+    // server_settings.asset_folder =
+    // // changes for each os
+    // if cfg!(target_os = "windows") || cfg!(target_os = "linux") || cfg!(debug_assertions){
+    //     "assets"
+    // } else if cfg!(target_os = "macos") {
+    //     "../Resources/assets"
+    // } else {
+    //     panic!("unsupported os")
+    // }.to_string();
+    let _blazma: Handle<Font> = server.load("Blazma/Blazma-Regular.ttf");
+    let _noto_sans: Handle<Font> =
+        server.load("Noto_Sans_Mono/NotoSansMono-VariableFont_wdth,wght.ttf");
+}
+
+fn menu_menu(mut commands: Commands, server: Res<AssetServer>) {
+    let blazma: Handle<Font> = server.load("Blazma/Blazma-Regular.ttf");
+
+    // spawn node bundle for buttons
+    let node = commands
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    width: Val::Percent(100.0),
+                    height: Val::Percent(100.0),
+                    align_items: AlignItems::Center,
+                    justify_content: JustifyContent::Center,
+                    ..default()
+                },
+                ..default()
+            },
+            TopLevelNode,
+        ))
+        .id();
+
+    spawn_menu_button(
+        &mut commands,
+        node,
+        "STEM",
+        blazma.clone(),
+        Some("test_start_game".to_string()),
+    );
+    spawn_menu_button(
+        &mut commands,
+        node,
+        "practice",
+        blazma.clone(),
+        Some("practice".to_string()),
+    );
+    spawn_menu_button(
+        &mut commands,
+        node,
+        "custom",
+        blazma.clone(),
+        Some("custom".to_string()),
+    );
+    spawn_menu_button(
+        &mut commands,
+        node,
+        "controls",
+        blazma.clone(),
+        Some("controls".to_string()),
+    );
+}
+
+pub fn spawn_menu_button(
+    commands: &mut Commands,
+    node: Entity,
+    text: &str,
+    font: Handle<Font>,
+    mbid: Option<String>,
+) -> Entity {
+    // Create a single animation (tween) to move an entity.
+    let hover_tween = Tween::new(
+        // Use a quadratic easing on both endpoints.
+        EaseFunction::QuadraticInOut,
+        // Animation time (one way only; for ping-pong it takes 2 seconds
+        // to come back to start).
+        Duration::from_secs(1),
+        // The lens gives the Animator access to the Transform component,
+        // to animate it. It also contains the start and end values associated
+        // with the animation ratios 0. and 1.
+        UiBackgroundColorLens {
+            end: NORMAL_BUTTON_COLOR,
+            start: HOVERED_BUTTON_COLOR,
+        },
+    )
+    // Repeat twice (one per way)
+    .with_repeat_count(RepeatCount::Infinite)
+    // After each iteration, reverse direction (ping-pong)
+    .with_repeat_strategy(RepeatStrategy::MirroredRepeat);
+    commands
+        .entity(node)
+        .with_children(|parent| {
+            parent
+                .spawn((
+                    ButtonBundle {
+                        style: Style {
+                            width: Val::Px(150.0),
+                            height: Val::Px(65.0),
+                            // horizontally center child text
+                            justify_content: JustifyContent::Center,
+                            // vertically center child text
+                            align_items: AlignItems::Center,
+                            ..default()
+                        },
+                        background_color: NORMAL_BUTTON_COLOR.into(),
+                        ..default()
+                    },
+                    MenuButtonId(mbid),
+                    Animator::new(hover_tween),
+                ))
+                .with_children(|parent| {
+                    parent.spawn(TextBundle::from_section(
+                        text,
+                        TextStyle {
+                            font,
+                            font_size: 40.0,
+                            color: Color::rgb(0.9, 0.9, 0.9),
+                        },
+                    ));
+                });
+        })
+        .id()
+}
+
+fn menu_update(
+    mut commands: Commands,
+    mut menu_button_pressed: EventReader<MenuButtonPressed>,
+    _app_state: ResMut<State<AppState>>,
+    mut next_state: ResMut<NextState<AppState>>,
+) {
+    for MenuButtonPressed(id) in &mut menu_button_pressed.read() {
+        match id.as_str() {
+            "test_start_game" => {
+                commands.insert_resource(MatchConfig::default());
+                next_state.set(AppState::InGame);
+            }
+            "practice" => {
+                commands.insert_resource(MatchConfig::practice(Difficulty::default()));
+                next_state.set(AppState::InGame);
+            }
+            "custom" => next_state.set(AppState::Setup),
+            "controls" => next_state.set(AppState::Controls),
+            _ => {}
+        }
+    }
+}
+
+#[allow(clippy::type_complexity)]
+fn input_system(
+    interaction_query: Query<
+        (Entity, &Interaction, &MenuButtonId),
+        (Changed<Interaction>, With<Button>),
+    >,
+    mut focus: ResMut<MenuFocus>,
+    mut menu_pressed: EventWriter<MenuButtonPressed>,
+) {
+    for (entity, interaction, mbid) in &interaction_query {
+        match *interaction {
+            Interaction::Pressed => {
+                if let Some(id) = &mbid.0 {
+                    menu_pressed.send(MenuButtonPressed(id.clone()));
+                }
+            }
+            // hovering moves the focus, which plays the hover tween
+            Interaction::Hovered => focus.0 = Some(entity),
+            // the focus stays put for keyboard and gamepad players
+            Interaction::None => {}
+        };
+    }
+}
+
+#[cfg(feature = "discord")]
+fn setup_discord(world: &mut World) {
+    let discord = Discord::with_create_flags(
+        1_023_380_299_821_875_210,
+        discord_game_sdk::CreateFlags::NoRequireDiscord,
+    );
+    match discord {
+        Ok(discord) => {
+            world.insert_non_send_resource(DiscordState(discord));
+        }
+        Err(_e) => {
+            println!("warning: discord setup failed...");
+        }
+    }
+}
+
+#[cfg(feature = "discord")]
+fn discord_update(discord: Option<NonSendMut<DiscordState>>) {
+    // TODO: add more states
+    if discord.is_none() {
+        return;
+    }
+    let mut discord = discord.unwrap();
+    discord.0.run_callbacks().unwrap();
+    let mut activity = discord_game_sdk::Activity::empty();
+    let activity = activity
+        // party status
+        .with_state("idle")
+        .with_large_image_key("logo")
+        .with_large_image_key("logo")
+        // player status
+        .with_details("in the menus");
+    discord.0.update_activity(activity, |_, result| {
+        if let Err(e) = result {
+            println!("Error updating activity: {}", e);
+        }
+    });
+}
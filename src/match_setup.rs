@@ -0,0 +1,458 @@
+use std::time::Duration;
+
+use bevy::{asset::LoadedFolder, prelude::*};
+
+use crate::{
+    ai::Difficulty,
+    arena::Arenas,
+    game::BallSpeed,
+    player::{
+        conflict_warning,
+        with_keyboard_layout,
+        Bindings,
+        Controller,
+        KeyboardLayout,
+        Roster,
+    },
+    rules::MatchRules,
+    spawn_menu_button,
+    AppState,
+    MenuButtonId,
+    MenuButtonPressed,
+    RotationStep,
+    TopLevelNode,
+};
+
+/// Who's in each player slot.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Slot {
+    Human,
+    /// a human steering with the mouse
+    Mouse,
+    Computer(Difficulty),
+}
+
+impl Slot {
+    const CYCLE: [Self; 5] = [
+        Self::Human,
+        Self::Mouse,
+        Self::Computer(Difficulty::Easy),
+        Self::Computer(Difficulty::Normal),
+        Self::Computer(Difficulty::Hard),
+    ];
+
+    const fn label(self) -> &'static str {
+        match self {
+            Self::Human => "human",
+            Self::Mouse => "mouse",
+            Self::Computer(Difficulty::Easy) => "easy",
+            Self::Computer(Difficulty::Normal) => "normal",
+            Self::Computer(Difficulty::Hard) => "hard",
+        }
+    }
+}
+
+/// Which keys human players use.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum KeyboardSetup {
+    /// whatever was set on the controls screen
+    Saved,
+    /// the built-in layouts, in player order
+    Split,
+    /// the built-in layouts with the first two players swapped round
+    Swapped,
+}
+
+impl KeyboardSetup {
+    const CYCLE: [Self; 3] = [Self::Saved, Self::Split, Self::Swapped];
+
+    const fn label(self) -> &'static str {
+        match self {
+            Self::Saved => "saved",
+            Self::Split => "split",
+            Self::Swapped => "swapped",
+        }
+    }
+
+    /// The built-in layout for `player`, or `None` to use their saved keys.
+    const fn layout(self, player: usize) -> Option<KeyboardLayout> {
+        match self {
+            Self::Saved => None,
+            Self::Split => Some(KeyboardLayout::for_player(player)),
+            Self::Swapped => Some(KeyboardLayout::for_player(match player {
+                0 => 1,
+                1 => 0,
+                player => player,
+            })),
+        }
+    }
+}
+
+/// Optional tweaks to the standard game.
+#[derive(Clone, Debug)]
+pub struct Modifiers {
+    /// hitting the ball mid-rotation speeds it up
+    pub smash: bool,
+    /// spin bends the ball's path
+    pub curve: bool,
+    pub time_limit: Option<Duration>,
+    /// how far paddles turn per rotate press, or free spinning
+    pub rotation: RotationStep,
+}
+
+impl Default for Modifiers {
+    fn default() -> Self {
+        Self {
+            smash: true,
+            curve: true,
+            time_limit: None,
+            rotation: RotationStep::default(),
+        }
+    }
+}
+
+/// Everything picked on the setup screen. [`crate::game::setup_game`] builds
+/// the match from this.
+#[derive(Resource, Clone, Debug)]
+pub struct MatchConfig {
+    /// one per player; only the first `players` are used
+    pub slots: [Slot; MAX_PLAYERS],
+    pub players: usize,
+    pub arena: String,
+    pub keyboard: KeyboardSetup,
+    pub score_limit: u32,
+    /// multiplier on [`BallSpeed`]'s serve speed and increment
+    pub ball_speed: f32,
+    pub modifiers: Modifiers,
+}
+
+pub const MAX_PLAYERS: usize = 4;
+
+impl Default for MatchConfig {
+    fn default() -> Self {
+        Self {
+            slots: [Slot::Human; MAX_PLAYERS],
+            players: 2,
+            arena: "classic".to_string(),
+            keyboard: KeyboardSetup::Saved,
+            score_limit: MatchRules::default().target_score,
+            ball_speed: 1.0,
+            modifiers: Modifiers::default(),
+        }
+    }
+}
+
+impl MatchConfig {
+    /// One human against the computer.
+    pub fn practice(difficulty: Difficulty) -> Self {
+        let mut config = Self::default();
+        config.slots[1] = Slot::Computer(difficulty);
+        config
+    }
+
+    pub fn roster(&self, bindings: &Bindings) -> Roster {
+        let mut roster = Roster::with_players(self.players);
+        for (i, (player, slot)) in roster.0.iter_mut().zip(self.slots).enumerate() {
+            player.controller = match slot {
+                Slot::Human => Controller::Human(match self.keyboard.layout(i) {
+                    Some(layout) => with_keyboard_layout(bindings.get(i), layout),
+                    None => bindings.get(i),
+                }),
+                Slot::Mouse => Controller::Mouse,
+                Slot::Computer(difficulty) => Controller::Computer(difficulty),
+            };
+        }
+        roster
+    }
+
+    pub fn rules(&self) -> MatchRules {
+        MatchRules {
+            target_score: self.score_limit,
+            time_limit: self.modifiers.time_limit,
+            ..default()
+        }
+    }
+
+    pub fn ball_speed(&self) -> BallSpeed {
+        let default = BallSpeed::default();
+        BallSpeed {
+            serve: default.serve * self.ball_speed,
+            increment: default.increment * self.ball_speed,
+            smash_speed: if self.modifiers.smash {
+                default.smash_speed
+            } else {
+                0.0
+            },
+            smash_spin: if self.modifiers.smash {
+                default.smash_spin
+            } else {
+                0.0
+            },
+            curve: if self.modifiers.curve {
+                default.curve
+            } else {
+                0.0
+            },
+            ..default
+        }
+    }
+
+    /// What the button with this id should currently say.
+    fn label(&self, id: &str) -> Option<String> {
+        let on_off = |on| if on { "on" } else { "off" }.to_string();
+        Some(match id {
+            "setup_players" => self.players.to_string(),
+            "setup_arena" => self.arena.clone(),
+            "setup_keys" => self.keyboard.label().to_string(),
+            "setup_score" => self.score_limit.to_string(),
+            "setup_ball" => BALL_SPEEDS
+                .iter()
+                .find(|(speed, _)| *speed == self.ball_speed)
+                .map_or("?", |(_, name)| name)
+                .to_string(),
+            "setup_smash" => on_off(self.modifiers.smash),
+            "setup_rotation" => match self.modifiers.rotation {
+                RotationStep::Step(step) => format!("{step}"),
+                RotationStep::Free => "free".to_string(),
+            },
+            "setup_curve" => on_off(self.modifiers.curve),
+            "setup_timer" => self
+                .modifiers
+                .time_limit
+                .map_or("off".to_string(), |limit| {
+                    format!("{}:00", limit.as_secs() / 60)
+                }),
+            _ => {
+                let slot: usize = id.strip_prefix("setup_slot_")?.parse().ok()?;
+                if slot < self.players {
+                    self.slots[slot].label().to_string()
+                } else {
+                    "-".to_string()
+                }
+            }
+        })
+    }
+
+    /// Moves the setting behind this button on to its next value.
+    fn cycle(&mut self, id: &str, arenas: &[String]) {
+        match id {
+            "setup_players" => self.players = next(&[2, 3, 4], &self.players),
+            "setup_arena" if !arenas.is_empty() => self.arena = next(arenas, &self.arena),
+            "setup_keys" => self.keyboard = next(&KeyboardSetup::CYCLE, &self.keyboard),
+            "setup_score" => self.score_limit = next(&SCORE_LIMITS, &self.score_limit),
+            "setup_ball" => {
+                let speeds = BALL_SPEEDS.map(|(speed, _)| speed);
+                self.ball_speed = next(&speeds, &self.ball_speed);
+            }
+            "setup_smash" => self.modifiers.smash = !self.modifiers.smash,
+            "setup_rotation" => {
+                self.modifiers.rotation = next(&ROTATION_STEPS, &self.modifiers.rotation);
+            }
+            "setup_curve" => self.modifiers.curve = !self.modifiers.curve,
+            "setup_timer" => {
+                self.modifiers.time_limit = next(&TIME_LIMITS, &self.modifiers.time_limit);
+            }
+            _ => {
+                let slot = id
+                    .strip_prefix("setup_slot_")
+                    .and_then(|slot| slot.parse::<usize>().ok());
+                if let Some(slot) = slot.filter(|slot| *slot < self.players) {
+                    self.slots[slot] = next(&Slot::CYCLE, &self.slots[slot]);
+                }
+            }
+        }
+    }
+}
+
+const SCORE_LIMITS: [u32; 5] = [3, 5, 7, 11, 21];
+const BALL_SPEEDS: [(f32, &str); 3] = [(0.75, "slow"), (1.0, "normal"), (1.5, "fast")];
+const ROTATION_STEPS: [RotationStep; 3] = [
+    RotationStep::Step(90.0),
+    RotationStep::Step(45.0),
+    RotationStep::Free,
+];
+const TIME_LIMITS: [Option<Duration>; 3] = [
+    None,
+    Some(Duration::from_secs(120)),
+    Some(Duration::from_secs(300)),
+];
+
+/// The value after `current` in `options`, wrapping around.
+fn next<T: PartialEq + Clone>(options: &[T], current: &T) -> T {
+    let index = options
+        .iter()
+        .position(|o| o == current)
+        .map_or(0, |i| i + 1);
+    options[index % options.len()].clone()
+}
+
+fn spawn_row(
+    commands: &mut Commands,
+    column: Entity,
+    label: &str,
+    id: &str,
+    config: &MatchConfig,
+    font: &Handle<Font>,
+) {
+    let row = commands
+        .spawn(NodeBundle {
+            style: Style {
+                align_items: AlignItems::Center,
+                ..default()
+            },
+            ..default()
+        })
+        .with_children(|parent| {
+            parent.spawn(
+                TextBundle::from_section(
+                    label,
+                    TextStyle {
+                        font: font.clone(),
+                        font_size: 30.0,
+                        color: Color::rgb(0.9, 0.9, 0.9),
+                    },
+                )
+                .with_style(Style {
+                    width: Val::Px(160.0),
+                    ..default()
+                }),
+            );
+        })
+        .id();
+    commands.entity(column).add_child(row);
+    spawn_menu_button(
+        commands,
+        row,
+        &config.label(id).unwrap_or_default(),
+        font.clone(),
+        Some(id.to_string()),
+    );
+}
+
+/// Lists keys that more than one player has bound.
+#[derive(Component)]
+pub struct SetupWarning;
+
+pub fn setup_menu(
+    mut commands: Commands,
+    server: Res<AssetServer>,
+    config: Res<MatchConfig>,
+    bindings: Res<Bindings>,
+) {
+    let blazma: Handle<Font> = server.load("Blazma/Blazma-Regular.ttf");
+
+    let column = |commands: &mut Commands| {
+        commands
+            .spawn(NodeBundle {
+                style: Style {
+                    flex_direction: FlexDirection::Column,
+                    row_gap: Val::Px(5.0),
+                    margin: UiRect::horizontal(Val::Px(20.0)),
+                    ..default()
+                },
+                ..default()
+            })
+            .id()
+    };
+    let settings = column(&mut commands);
+    let players = column(&mut commands);
+    commands
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    width: Val::Percent(100.0),
+                    height: Val::Percent(100.0),
+                    align_items: AlignItems::Center,
+                    justify_content: JustifyContent::Center,
+                    ..default()
+                },
+                ..default()
+            },
+            TopLevelNode,
+        ))
+        .push_children(&[settings, players]);
+
+    for (label, id) in [
+        ("players", "setup_players"),
+        ("arena", "setup_arena"),
+        ("keys", "setup_keys"),
+        ("score", "setup_score"),
+        ("ball", "setup_ball"),
+        ("smash", "setup_smash"),
+        ("rotation", "setup_rotation"),
+        ("curve", "setup_curve"),
+        ("timer", "setup_timer"),
+    ] {
+        spawn_row(&mut commands, settings, label, id, &config, &blazma);
+    }
+    for slot in 0..MAX_PLAYERS {
+        let label = format!("p{}", slot + 1);
+        let id = format!("setup_slot_{slot}");
+        spawn_row(&mut commands, players, &label, &id, &config, &blazma);
+    }
+    spawn_menu_button(
+        &mut commands,
+        players,
+        "start",
+        blazma.clone(),
+        Some("setup_start".to_string()),
+    );
+    spawn_menu_button(
+        &mut commands,
+        players,
+        "back",
+        blazma.clone(),
+        Some("setup_back".to_string()),
+    );
+    commands.entity(players).with_children(|parent| {
+        parent.spawn((
+            TextBundle::from_section(
+                conflict_warning(&config.roster(&bindings).keyboard_conflicts()),
+                TextStyle {
+                    font: blazma,
+                    font_size: 20.0,
+                    color: Color::rgb(1.0, 0.6, 0.3),
+                },
+            ),
+            SetupWarning,
+        ));
+    });
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn setup_update(
+    mut menu_button_pressed: EventReader<MenuButtonPressed>,
+    mut config: ResMut<MatchConfig>,
+    mut next_state: ResMut<NextState<AppState>>,
+    arenas: Res<Arenas>,
+    folders: Res<Assets<LoadedFolder>>,
+    bindings: Res<Bindings>,
+    button_query: Query<(&MenuButtonId, &Children)>,
+    mut text_query: Query<&mut Text>,
+    warning_query: Query<Entity, With<SetupWarning>>,
+) {
+    for MenuButtonPressed(id) in menu_button_pressed.read() {
+        match id.as_str() {
+            "setup_start" => next_state.set(AppState::InGame),
+            "setup_back" => next_state.set(AppState::MainMenu),
+            id => config.cycle(id, &arenas.names(&folders)),
+        }
+    }
+    if !config.is_changed() {
+        return;
+    }
+    for (MenuButtonId(id), children) in &button_query {
+        let Some(label) = id.as_deref().and_then(|id| config.label(id)) else {
+            continue;
+        };
+        if let Ok(mut text) = text_query.get_mut(children[0]) {
+            text.sections[0].value = label;
+        }
+    }
+    let warning = conflict_warning(&config.roster(&bindings).keyboard_conflicts());
+    for entity in &warning_query {
+        if let Ok(mut text) = text_query.get_mut(entity) {
+            text.sections[0].value = warning.clone();
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use bevy::{prelude::*, utils::HashMap};
+use bevy_tweening::{Animator, AnimatorState};
+use leafwing_input_manager::prelude::*;
+
+use crate::{controls::ControlsScreen, MenuButtonId, MenuButtonPressed, NORMAL_BUTTON_COLOR};
+
+/// Moving around menus without the mouse. Every gamepad drives these.
+#[derive(Actionlike, PartialEq, Eq, Clone, Copy, Hash, Debug, Reflect)]
+pub enum MenuAction {
+    Up,
+    Down,
+    Left,
+    Right,
+    Confirm,
+    Back,
+}
+
+impl MenuAction {
+    /// which way on screen this moves the focus (ui y points down)
+    const fn direction(self) -> Option<Vec2> {
+        match self {
+            Self::Up => Some(Vec2::NEG_Y),
+            Self::Down => Some(Vec2::Y),
+            Self::Left => Some(Vec2::NEG_X),
+            Self::Right => Some(Vec2::X),
+            Self::Confirm | Self::Back => None,
+        }
+    }
+}
+
+/// Buttons that "back" presses, whichever one is on screen.
+const BACK_BUTTONS: [&str; 4] = ["setup_back", "controls_back", "resume", "back_to_menu"];
+
+/// how far the stick has to be pushed to move the focus
+const STICK_THRESHOLD: f32 = 0.5;
+
+pub fn menu_input_map() -> InputMap<MenuAction> {
+    use GamepadAxisType::{LeftStickX, LeftStickY};
+
+    let mut input_map = InputMap::new([
+        (KeyCode::Up, MenuAction::Up),
+        (KeyCode::W, MenuAction::Up),
+        (KeyCode::Down, MenuAction::Down),
+        (KeyCode::S, MenuAction::Down),
+        (KeyCode::Left, MenuAction::Left),
+        (KeyCode::A, MenuAction::Left),
+        (KeyCode::Right, MenuAction::Right),
+        (KeyCode::D, MenuAction::Right),
+        (KeyCode::Return, MenuAction::Confirm),
+        (KeyCode::Space, MenuAction::Confirm),
+        (KeyCode::Escape, MenuAction::Back),
+        (KeyCode::Back, MenuAction::Back),
+    ]);
+    input_map
+        .insert(GamepadButtonType::DPadUp, MenuAction::Up)
+        .insert(GamepadButtonType::DPadDown, MenuAction::Down)
+        .insert(GamepadButtonType::DPadLeft, MenuAction::Left)
+        .insert(GamepadButtonType::DPadRight, MenuAction::Right)
+        .insert(GamepadButtonType::South, MenuAction::Confirm)
+        .insert(GamepadButtonType::East, MenuAction::Back)
+        .insert(
+            SingleAxis::positive_only(LeftStickY, STICK_THRESHOLD),
+            MenuAction::Up,
+        )
+        .insert(
+            SingleAxis::negative_only(LeftStickY, STICK_THRESHOLD),
+            MenuAction::Down,
+        )
+        .insert(
+            SingleAxis::negative_only(LeftStickX, STICK_THRESHOLD),
+            MenuAction::Left,
+        )
+        .insert(
+            SingleAxis::positive_only(LeftStickX, STICK_THRESHOLD),
+            MenuAction::Right,
+        );
+    input_map
+}
+
+/// The menu button that confirm presses. Hovering a button with the mouse
+/// focuses it too.
+#[derive(Resource, Default, Debug)]
+pub struct MenuFocus(pub Option<Entity>);
+
+/// For each menu button, the button to focus next in each direction.
+#[derive(Resource, Default, Debug)]
+pub struct FocusGraph(HashMap<Entity, [Option<Entity>; 4]>);
+
+const DIRECTIONS: [MenuAction; 4] = [
+    MenuAction::Up,
+    MenuAction::Down,
+    MenuAction::Left,
+    MenuAction::Right,
+];
+
+/// The closest button from `from` in `direction`, preferring ones in line
+/// with it over ones that are nearer but off to the side.
+fn neighbour(from: Vec2, direction: Vec2, buttons: &[(Entity, Vec2)]) -> Option<Entity> {
+    buttons
+        .iter()
+        .filter_map(|(entity, position)| {
+            let offset = *position - from;
+            let along = offset.dot(direction);
+            if along <= 1.0 {
+                return None;
+            }
+            let across = (offset - direction * along).length();
+            Some((*entity, 2.0f32.mul_add(across, along)))
+        })
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(entity, _)| entity)
+}
+
+/// Rebuilds the [`FocusGraph`] whenever menu buttons appear or move.
+#[allow(clippy::type_complexity)]
+pub fn build_focus_graph(
+    mut graph: ResMut<FocusGraph>,
+    changed: Query<(), (With<MenuButtonId>, Changed<GlobalTransform>)>,
+    mut removed: RemovedComponents<MenuButtonId>,
+    button_query: Query<(Entity, &GlobalTransform), (With<Button>, With<MenuButtonId>)>,
+) {
+    if changed.is_empty() && removed.read().next().is_none() {
+        return;
+    }
+    let buttons: Vec<(Entity, Vec2)> = button_query
+        .iter()
+        .map(|(entity, transform)| (entity, transform.translation().truncate()))
+        .collect();
+    graph.0 = buttons
+        .iter()
+        .map(|(entity, position)| {
+            let links = DIRECTIONS.map(|action| {
+                action
+                    .direction()
+                    .and_then(|direction| neighbour(*position, direction, &buttons))
+            });
+            (*entity, links)
+        })
+        .collect();
+}
+
+/// Moves the focus around the [`FocusGraph`], and turns confirm and back
+/// into [`MenuButtonPressed`] events.
+pub fn menu_navigation(
+    action_state: Res<ActionState<MenuAction>>,
+    graph: Res<FocusGraph>,
+    mut focus: ResMut<MenuFocus>,
+    button_query: Query<(Entity, &MenuButtonId, &GlobalTransform), With<Button>>,
+    mut menu_pressed: EventWriter<MenuButtonPressed>,
+    controls: Option<Res<ControlsScreen>>,
+) {
+    // the controls screen wants the next key for itself
+    if controls.is_some_and(|controls| controls.listening.is_some()) {
+        return;
+    }
+    if action_state.just_pressed(MenuAction::Back) {
+        let back = button_query
+            .iter()
+            .filter_map(|(_, MenuButtonId(id), _)| id.as_deref())
+            .find(|id| BACK_BUTTONS.contains(id));
+        if let Some(id) = back {
+            menu_pressed.send(MenuButtonPressed(id.to_string()));
+        }
+    }
+
+    let focused = focus
+        .0
+        .filter(|entity| button_query.contains(*entity) && graph.0.contains_key(entity));
+    let Some(focused) = focused else {
+        // the first press on a new screen just picks the top left button
+        let pressed = DIRECTIONS
+            .into_iter()
+            .chain([MenuAction::Confirm])
+            .any(|action| action_state.just_pressed(action));
+        if pressed {
+            focus.0 = button_query
+                .iter()
+                .map(|(entity, _, transform)| (entity, transform.translation()))
+                .min_by(|(_, a), (_, b)| a.y.total_cmp(&b.y).then(a.x.total_cmp(&b.x)))
+                .map(|(entity, _)| entity);
+        }
+        return;
+    };
+
+    for (i, action) in DIRECTIONS.into_iter().enumerate() {
+        if action_state.just_pressed(action) {
+            if let Some(next) = graph.0[&focused][i] {
+                focus.0 = Some(next);
+            }
+        }
+    }
+    if action_state.just_pressed(MenuAction::Confirm) {
+        if let Ok((_, MenuButtonId(Some(id)), _)) = button_query.get(focused) {
+            menu_pressed.send(MenuButtonPressed(id.clone()));
+        }
+    }
+}
+
+/// Plays the hover tween on the focused button and stops it on the rest.
+pub fn highlight_focus(
+    focus: Res<MenuFocus>,
+    mut button_query: Query<
+        (Entity, &mut Animator<BackgroundColor>, &mut BackgroundColor),
+        With<MenuButtonId>,
+    >,
+) {
+    if !focus.is_changed() {
+        return;
+    }
+    for (entity, mut anim, mut color) in &mut button_query {
+        if focus.0 == Some(entity) {
+            anim.state = AnimatorState::Playing;
+        } else {
+            anim.stop();
+            *color = NORMAL_BUTTON_COLOR.into();
+        }
+    }
+}
@@ -0,0 +1,209 @@
+//! What online play has in common, whether it's peer to peer or through a
+//! server: sending a player's input, and handing paddles over to it.
+
+use bevy::prelude::*;
+use leafwing_input_manager::{axislike::DualAxisData, prelude::*};
+
+use crate::{
+    calc,
+    player::{give_gamepad, Controller, Player, RemoteInput, Roster},
+    Action,
+    Paddle,
+    RotationQueue,
+};
+
+/// One player's input for one tick, as sent over the network.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct NetInput {
+    /// a bit for each of [`NetInput::BUTTONS`] that's held
+    pub buttons: u8,
+    /// the analog stick, scaled to ±127
+    pub stick: [i8; 2],
+}
+
+impl NetInput {
+    pub const BUTTONS: [Action; 6] = [
+        Action::Left,
+        Action::Right,
+        Action::Up,
+        Action::Down,
+        Action::RotateClockwise,
+        Action::RotateAntiClockwise,
+    ];
+    pub const SIZE: usize = 3;
+
+    pub fn from_action_state(action_state: &ActionState<Action>) -> Self {
+        let buttons = Self::BUTTONS
+            .iter()
+            .enumerate()
+            .filter(|(_, action)| action_state.pressed(**action))
+            .fold(0, |buttons, (i, _)| buttons | 1 << i);
+        let stick = action_state
+            .axis_pair(Action::Move)
+            .map_or(Vec2::ZERO, |stick| stick.xy())
+            .clamp(Vec2::NEG_ONE, Vec2::ONE)
+            * 127.0;
+        Self {
+            buttons,
+            stick: [stick.x.round() as i8, stick.y.round() as i8],
+        }
+    }
+
+    pub fn pressed(self, action: Action) -> bool {
+        Self::BUTTONS
+            .iter()
+            .position(|button| *button == action)
+            .is_some_and(|i| self.buttons & 1 << i != 0)
+    }
+
+    pub fn stick(self) -> Vec2 {
+        Vec2::new(f32::from(self.stick[0]), f32::from(self.stick[1])) / 127.0
+    }
+
+    /// Sets a paddle's actions to this input, and queues any rotate presses
+    /// that weren't held in `previous`.
+    pub fn apply(
+        self,
+        action_state: &mut ActionState<Action>,
+        queue: &mut RotationQueue,
+        previous: Self,
+    ) {
+        for action in Self::BUTTONS {
+            if self.pressed(action) {
+                action_state.press(action);
+            } else {
+                action_state.release(action);
+            }
+        }
+        action_state.action_data_mut(Action::Move).axis_pair =
+            Some(DualAxisData::from_xy(self.stick()));
+        for (action, clockwise) in [
+            (Action::RotateAntiClockwise, false),
+            (Action::RotateClockwise, true),
+        ] {
+            if self.pressed(action) && !previous.pressed(action) {
+                calc::queue_rotation(queue, clockwise);
+            }
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        [
+            self.buttons,
+            self.stick[0].to_le_bytes()[0],
+            self.stick[1].to_le_bytes()[0],
+        ]
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        Self {
+            buttons: bytes[0],
+            stick: [i8::from_le_bytes([bytes[1]]), i8::from_le_bytes([bytes[2]])],
+        }
+    }
+}
+
+/// Takes the paddles of a newly started match away from their bindings, so
+/// only [`NetInput::apply`] moves them. The local player's bindings move to
+/// the [`ActionState`] resource, to be read and sent, with the first gamepad:
+/// they're the only one playing here, whichever player they are. Returns
+/// whether there were any new paddles.
+pub fn take_over_paddles(world: &mut World, local_player: Option<usize>) -> bool {
+    let mut new_paddles =
+        world.query_filtered::<(Entity, &Player), (With<Paddle>, Without<RemoteInput>)>();
+    let paddles: Vec<(Entity, usize)> = new_paddles
+        .iter(world)
+        .map(|(entity, player)| (entity, player.0))
+        .collect();
+    for &(entity, player) in &paddles {
+        let mut paddle = world.entity_mut(entity);
+        let input_map = paddle.take::<InputMap<Action>>();
+        paddle.insert(RemoteInput);
+        if Some(player) == local_player {
+            // the roster's copy still has the gamepad bindings, even if the
+            // paddle's was left without a gamepad
+            let bindings = world
+                .get_resource::<Roster>()
+                .and_then(|roster| match &roster.0.get(player)?.controller {
+                    Controller::Human(bindings) => Some(bindings.clone()),
+                    _ => None,
+                })
+                .or(input_map);
+            if let Some(mut input_map) = bindings {
+                let gamepad = world
+                    .get_resource::<Gamepads>()
+                    .and_then(|gamepads| gamepads.iter().min_by_key(|gamepad| gamepad.id));
+                give_gamepad(&mut input_map, gamepad);
+                world.insert_resource(input_map);
+            }
+            world.init_resource::<ActionState<Action>>();
+        }
+    }
+    !paddles.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn net_input_keeps_held_actions() {
+        let mut action_state = ActionState::<Action>::default();
+        action_state.press(Action::Up);
+        action_state.press(Action::RotateClockwise);
+        action_state.action_data_mut(Action::Move).axis_pair = Some(DualAxisData::new(0.5, -1.0));
+        let input = NetInput::from_action_state(&action_state);
+        assert!(input.pressed(Action::Up));
+        assert!(input.pressed(Action::RotateClockwise));
+        assert!(!input.pressed(Action::Down));
+        assert!(!input.pressed(Action::Move));
+        assert!((input.stick() - Vec2::new(0.5, -1.0)).length() < 0.01);
+    }
+
+    #[test]
+    fn applying_input_queues_only_new_rotate_presses() {
+        let mut action_state = ActionState::<Action>::default();
+        let mut queue = RotationQueue::default();
+        let mut source = ActionState::<Action>::default();
+        source.press(Action::RotateClockwise);
+        source.press(Action::Left);
+        let input = NetInput::from_action_state(&source);
+        input.apply(&mut action_state, &mut queue, NetInput::default());
+        assert!(action_state.pressed(Action::Left));
+        assert_eq!(queue.0.len(), 1);
+        // still held, so not queued again
+        input.apply(&mut action_state, &mut queue, input);
+        assert_eq!(queue.0.len(), 1);
+        NetInput::default().apply(&mut action_state, &mut queue, input);
+        assert!(!action_state.pressed(Action::Left));
+    }
+
+    #[test]
+    fn the_local_player_gets_the_gamepad() {
+        use bevy::input::gamepad::{GamepadConnection, GamepadConnectionEvent, GamepadInfo};
+
+        use crate::{
+            headless::{headless_app, start_match},
+            match_setup::MatchConfig,
+        };
+
+        let mut app = headless_app(MatchConfig::default());
+        app.world.send_event(GamepadConnectionEvent::new(
+            Gamepad::new(0),
+            GamepadConnection::Connected(GamepadInfo {
+                name: "pad".to_string(),
+            }),
+        ));
+        // player 1's paddle is left without a gamepad here, as player 0
+        // would get the only one in a local match
+        start_match(&mut app);
+        take_over_paddles(&mut app.world, Some(1));
+        let input_map = app.world.resource::<InputMap<Action>>();
+        assert_eq!(input_map.gamepad(), Some(Gamepad::new(0)));
+        assert!(input_map
+            .get(Action::RotateClockwise)
+            .is_some_and(|inputs| inputs
+                .iter()
+                .any(|input| !input.raw_inputs().gamepad_buttons.is_empty())));
+    }
+}
@@ -0,0 +1,139 @@
+use bevy::prelude::*;
+
+use crate::{
+    game::{despawn_game, GameEntity},
+    prompts::prompt_glyph_bundle,
+    spawn_menu_button,
+    Action,
+    AppState,
+    MenuButtonPressed,
+    TopLevelNode,
+};
+
+/// Escape on the keyboard, or start on any gamepad.
+fn pause_pressed(
+    keys: &Input<KeyCode>,
+    gamepads: &Gamepads,
+    gamepad_buttons: &Input<GamepadButton>,
+) -> bool {
+    keys.just_pressed(KeyCode::Escape)
+        || gamepads.iter().any(|gamepad| {
+            gamepad_buttons.just_pressed(GamepadButton::new(gamepad, GamepadButtonType::Start))
+        })
+}
+
+/// Toggles between [`AppState::InGame`] and [`AppState::Paused`].
+pub fn pause_input(
+    keys: Res<Input<KeyCode>>,
+    gamepads: Res<Gamepads>,
+    gamepad_buttons: Res<Input<GamepadButton>>,
+    app_state: Res<State<AppState>>,
+    mut next_state: ResMut<NextState<AppState>>,
+) {
+    if !pause_pressed(&keys, &gamepads, &gamepad_buttons) {
+        return;
+    }
+    match app_state.get() {
+        AppState::InGame => next_state.set(AppState::Paused),
+        AppState::Paused => next_state.set(AppState::InGame),
+        _ => {}
+    }
+}
+
+pub fn pause_menu(mut commands: Commands, server: Res<AssetServer>) {
+    let blazma: Handle<Font> = server.load("Blazma/Blazma-Regular.ttf");
+
+    // dim the (frozen) game behind the menu
+    let node = commands
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    width: Val::Percent(100.0),
+                    height: Val::Percent(100.0),
+                    flex_direction: FlexDirection::Column,
+                    align_items: AlignItems::Center,
+                    justify_content: JustifyContent::Center,
+                    row_gap: Val::Px(10.0),
+                    ..default()
+                },
+                background_color: Color::rgba(0.0, 0.0, 0.0, 0.6).into(),
+                ..default()
+            },
+            TopLevelNode,
+        ))
+        .with_children(|parent| {
+            parent.spawn(TextBundle::from_section(
+                "paused",
+                TextStyle {
+                    font: blazma.clone(),
+                    font_size: 60.0,
+                    color: Color::rgb(0.9, 0.9, 0.9),
+                },
+            ));
+            // reminder of the controls, in whatever player 1 is holding
+            parent
+                .spawn(NodeBundle {
+                    style: Style {
+                        align_items: AlignItems::Center,
+                        column_gap: Val::Px(5.0),
+                        ..default()
+                    },
+                    ..default()
+                })
+                .with_children(|parent| {
+                    let text = |text: &str| {
+                        TextBundle::from_section(
+                            text,
+                            TextStyle {
+                                font: blazma.clone(),
+                                font_size: 30.0,
+                                color: Color::rgb(0.9, 0.9, 0.9),
+                            },
+                        )
+                    };
+                    parent.spawn(text("press"));
+                    parent.spawn(prompt_glyph_bundle(0, Action::RotateAntiClockwise));
+                    parent.spawn(prompt_glyph_bundle(0, Action::RotateClockwise));
+                    parent.spawn(text("to rotate"));
+                });
+        })
+        .id();
+
+    for (text, id) in [
+        ("resume", "resume"),
+        ("restart", "restart"),
+        ("settings", "settings"),
+        ("quit", "quit_to_menu"),
+    ] {
+        spawn_menu_button(
+            &mut commands,
+            node,
+            text,
+            blazma.clone(),
+            Some(id.to_string()),
+        );
+    }
+}
+
+pub fn pause_update(
+    mut commands: Commands,
+    mut menu_button_pressed: EventReader<MenuButtonPressed>,
+    mut next_state: ResMut<NextState<AppState>>,
+    game_query: Query<Entity, With<GameEntity>>,
+) {
+    for MenuButtonPressed(id) in menu_button_pressed.read() {
+        match id.as_str() {
+            "resume" => next_state.set(AppState::InGame),
+            "restart" => {
+                despawn_game(&mut commands, &game_query);
+                next_state.set(AppState::InGame);
+            }
+            "settings" => next_state.set(AppState::Controls),
+            "quit_to_menu" => {
+                despawn_game(&mut commands, &game_query);
+                next_state.set(AppState::MainMenu);
+            }
+            _ => {}
+        }
+    }
+}
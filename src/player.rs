@@ -0,0 +1,534 @@
+use std::{fs, io, path::PathBuf};
+
+use bevy::{prelude::*, window::PrimaryWindow};
+use bevy_rapier2d::prelude::Velocity;
+use leafwing_input_manager::{
+    axislike::{AxisType, DualAxisData},
+    prelude::*,
+};
+use thiserror::Error;
+
+use crate::{ai::Difficulty, calc, game::Side, match_setup::MAX_PLAYERS, Action};
+
+/// Index of the local player controlling a paddle.
+#[derive(Component, Reflect, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[reflect(Component)]
+pub struct Player(pub usize);
+
+/// What's driving a paddle.
+#[derive(Clone, Debug)]
+pub enum Controller {
+    Human(InputMap<Action>),
+    Mouse,
+    Computer(Difficulty),
+}
+
+/// Everything needed to spawn one local player's paddle.
+#[derive(Clone, Debug)]
+pub struct PlayerConfig {
+    pub controller: Controller,
+    pub side: Side,
+    pub spawn: Vec2,
+    pub colour: Color,
+}
+
+/// The local players taking part in the next match, one paddle each.
+#[derive(Resource, Clone, Debug)]
+pub struct Roster(pub Vec<PlayerConfig>);
+
+impl Default for Roster {
+    fn default() -> Self {
+        Self::with_players(2)
+    }
+}
+
+impl Roster {
+    /// Sets up `count` players, alternating between the left and right side
+    /// and spreading out vertically when a side has more than one paddle.
+    pub fn with_players(count: usize) -> Self {
+        Self(
+            (0..count)
+                .map(|i| {
+                    let side = if i % 2 == 0 { Side::Left } else { Side::Right };
+                    // how many paddles share this side, and which one this is
+                    let on_side = (count + 1 - i % 2) / 2;
+                    let slot = i / 2;
+                    PlayerConfig {
+                        controller: Controller::Human(default_input_map(i)),
+                        side,
+                        spawn: Vec2::new(side.sign() * 400.0, spread(slot, on_side, SPAWN_HEIGHT)),
+                        colour: PLAYER_COLOURS[i % PLAYER_COLOURS.len()],
+                    }
+                })
+                .collect(),
+        )
+    }
+}
+
+/// A key bound for more than one local player.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct KeyConflict {
+    pub key: KeyCode,
+    /// in player order, so the first one keeps it
+    pub players: Vec<usize>,
+}
+
+/// Every key in more than one of `input_maps`, which are numbered by player.
+fn keyboard_conflicts<'a>(
+    input_maps: impl Iterator<Item = (usize, &'a InputMap<Action>)>,
+) -> Vec<KeyConflict> {
+    let mut users: Vec<KeyConflict> = Vec::new();
+    for (player, input_map) in input_maps {
+        let keys = input_map
+            .iter()
+            .flat_map(|(_, inputs)| inputs)
+            .flat_map(|input| input.raw_inputs().keycodes);
+        for key in keys {
+            match users.iter_mut().find(|used| used.key == key) {
+                Some(used) if !used.players.contains(&player) => used.players.push(player),
+                Some(_) => {}
+                None => users.push(KeyConflict {
+                    key,
+                    players: vec![player],
+                }),
+            }
+        }
+    }
+    users.retain(|used| used.players.len() > 1);
+    users
+}
+
+/// Takes every conflicting key away from all but the first player that has
+/// it.
+fn resolve_conflicts<'a>(
+    conflicts: &[KeyConflict],
+    mut input_map: impl FnMut(usize) -> Option<&'a mut InputMap<Action>>,
+) {
+    for conflict in conflicts {
+        for player in &conflict.players[1..] {
+            let Some(input_map) = input_map(*player) else {
+                continue;
+            };
+            for action in Action::variants() {
+                if let Some(inputs) = input_map.get_mut(action) {
+                    inputs.retain(|input| !input.raw_inputs().keycodes.contains(&conflict.key));
+                }
+            }
+        }
+    }
+}
+
+impl Roster {
+    /// Every key that would drive more than one human player's paddle.
+    pub fn keyboard_conflicts(&self) -> Vec<KeyConflict> {
+        keyboard_conflicts(self.0.iter().enumerate().filter_map(|(player, config)| {
+            match &config.controller {
+                Controller::Human(input_map) => Some((player, input_map)),
+                _ => None,
+            }
+        }))
+    }
+
+    /// Takes every conflicting key away from all but the first player that
+    /// has it, so no key drives two paddles.
+    pub fn resolve_conflicts(&mut self) -> Vec<KeyConflict> {
+        let conflicts = self.keyboard_conflicts();
+        let mut input_maps: Vec<Option<&mut InputMap<Action>>> = self
+            .0
+            .iter_mut()
+            .map(|config| match &mut config.controller {
+                Controller::Human(input_map) => Some(input_map),
+                _ => None,
+            })
+            .collect();
+        resolve_conflicts(&conflicts, |player| input_maps[player].take());
+        conflicts
+    }
+}
+
+/// Describes `conflicts` for the player, one line each.
+pub fn conflict_warning(conflicts: &[KeyConflict]) -> String {
+    conflicts
+        .iter()
+        .map(|conflict| {
+            let players: Vec<String> = conflict
+                .players
+                .iter()
+                .map(|player| format!("p{}", player + 1))
+                .collect();
+            format!(
+                "{:?} is bound for {}, only {} gets it",
+                conflict.key,
+                players.join(" and "),
+                players[0]
+            )
+            .to_lowercase()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+const SPAWN_HEIGHT: f32 = 400.0;
+
+const PLAYER_COLOURS: [Color; 4] = [
+    Color::rgb(0.5, 0.5, 1.0),
+    Color::rgb(1.0, 0.5, 0.5),
+    Color::rgb(0.5, 1.0, 0.5),
+    Color::rgb(1.0, 1.0, 0.5),
+];
+
+/// Evenly spaces `count` things along `height`, centred on 0.
+fn spread(index: usize, count: usize, height: f32) -> f32 {
+    if count <= 1 {
+        return 0.0;
+    }
+    let step = height / count as f32;
+    (index as f32 + 0.5).mul_add(step, -height / 2.0)
+}
+
+/// The bindings each player slot starts with: its own set of keys, plus the
+/// standard gamepad layout for whichever controller it gets handed.
+pub fn default_input_map(player: usize) -> InputMap<Action> {
+    let mut input_map = default_keyboard_map(player);
+    input_map.merge(&default_gamepad_map());
+    input_map
+}
+
+fn default_keyboard_map(player: usize) -> InputMap<Action> {
+    KeyboardLayout::for_player(player).input_map()
+}
+
+/// Sets of keys far enough apart for several people to share a keyboard.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum KeyboardLayout {
+    /// WASD to move, C/V to rotate
+    Wasd,
+    /// arrow keys to move, comma/full stop to rotate
+    Arrows,
+    /// IJKL to move, N/M to rotate
+    Ijkl,
+    /// numpad 8456 to move, 7/9 to rotate
+    Numpad,
+}
+
+impl KeyboardLayout {
+    /// The layout each player slot gets by default.
+    pub const fn for_player(player: usize) -> Self {
+        match player % 4 {
+            0 => Self::Wasd,
+            1 => Self::Arrows,
+            2 => Self::Ijkl,
+            _ => Self::Numpad,
+        }
+    }
+
+    pub fn input_map(self) -> InputMap<Action> {
+        let keys = match self {
+            Self::Wasd => [
+                KeyCode::A,
+                KeyCode::D,
+                KeyCode::W,
+                KeyCode::S,
+                KeyCode::C,
+                KeyCode::V,
+            ],
+            Self::Arrows => [
+                KeyCode::Left,
+                KeyCode::Right,
+                KeyCode::Up,
+                KeyCode::Down,
+                KeyCode::Comma,
+                KeyCode::Period,
+            ],
+            Self::Ijkl => [
+                KeyCode::J,
+                KeyCode::L,
+                KeyCode::I,
+                KeyCode::K,
+                KeyCode::N,
+                KeyCode::M,
+            ],
+            Self::Numpad => [
+                KeyCode::Numpad4,
+                KeyCode::Numpad6,
+                KeyCode::Numpad8,
+                KeyCode::Numpad5,
+                KeyCode::Numpad7,
+                KeyCode::Numpad9,
+            ],
+        };
+        InputMap::new(keys.into_iter().zip([
+            Action::Left,
+            Action::Right,
+            Action::Up,
+            Action::Down,
+            Action::RotateAntiClockwise,
+            Action::RotateClockwise,
+        ]))
+    }
+}
+
+/// `input_map` with its keys swapped for `layout`, keeping everything else.
+pub fn with_keyboard_layout(
+    mut input_map: InputMap<Action>,
+    layout: KeyboardLayout,
+) -> InputMap<Action> {
+    for action in Action::variants() {
+        if let Some(inputs) = input_map.get_mut(action) {
+            inputs.retain(|input| input.raw_inputs().keycodes.is_empty());
+        }
+    }
+    input_map.merge(&layout.input_map());
+    input_map
+}
+
+/// The same for every player; [`assign_gamepads`] decides whose gamepad it
+/// reads from.
+pub fn default_gamepad_map() -> InputMap<Action> {
+    use GamepadButtonType::{
+        DPadDown,
+        DPadLeft,
+        DPadRight,
+        DPadUp,
+        East,
+        LeftTrigger,
+        RightTrigger,
+        West,
+    };
+
+    let mut input_map = InputMap::new([
+        (DPadLeft, Action::Left),
+        (DPadRight, Action::Right),
+        (DPadUp, Action::Up),
+        (DPadDown, Action::Down),
+        // bevy calls the shoulder buttons triggers
+        (LeftTrigger, Action::RotateAntiClockwise),
+        (RightTrigger, Action::RotateClockwise),
+        (West, Action::RotateAntiClockwise),
+        (East, Action::RotateClockwise),
+    ]);
+    input_map.insert(move_stick(), Action::Move);
+    input_map
+}
+
+/// The left stick, for analog movement.
+fn move_stick() -> DualAxis {
+    DualAxis::left_stick().with_deadzone(DeadZoneShape::Ellipse {
+        radius_x: calc::STICK_DEADZONE,
+        radius_y: calc::STICK_DEADZONE,
+    })
+}
+
+/// The fixed bindings for the mouse scheme. Movement comes from
+/// [`mouse_control`] instead.
+pub fn mouse_input_map() -> InputMap<Action> {
+    InputMap::new([
+        (MouseWheelDirection::Up, Action::RotateAntiClockwise),
+        (MouseWheelDirection::Down, Action::RotateClockwise),
+    ])
+    .insert(MouseButton::Left, Action::RotateAntiClockwise)
+    .insert(MouseButton::Right, Action::RotateClockwise)
+    .build()
+}
+
+fn is_gamepad_input(input: &UserInput) -> bool {
+    let raw = input.raw_inputs();
+    !raw.gamepad_buttons.is_empty()
+        || raw
+            .axis_data
+            .iter()
+            .any(|(axis, _)| matches!(axis, AxisType::Gamepad(_)))
+}
+
+/// Hands out connected gamepads to human players in player order, whenever a
+/// gamepad connects or disconnects, the controls change or new paddles are
+/// spawned. Players left without one lose their gamepad bindings, since an
+/// [`InputMap`] with no gamepad set listens to all of them.
+pub fn assign_gamepads(
+    gamepads: Res<Gamepads>,
+    roster: Res<Roster>,
+    mut query: Query<(&Player, &mut InputMap<Action>)>,
+) {
+    // a separate `Added` query would conflict with the mutable one
+    let added = query.iter_mut().any(|(_, input_map)| input_map.is_added());
+    if !gamepads.is_changed() && !roster.is_changed() && !added {
+        return;
+    }
+    let mut connected: Vec<Gamepad> = gamepads.iter().collect();
+    connected.sort_by_key(|gamepad| gamepad.id);
+
+    let mut players: Vec<_> = query.iter_mut().collect();
+    players.sort_by_key(|(player, _)| player.0);
+    let mut connected = connected.into_iter();
+    for (player, mut input_map) in players {
+        let Some(Controller::Human(bindings)) = roster.0.get(player.0).map(|p| &p.controller)
+        else {
+            continue;
+        };
+        let mut bindings = bindings.clone();
+        let gamepad = connected.next();
+        if let Some(gamepad) = gamepad {
+            info!("player {} is using gamepad {}", player.0 + 1, gamepad.id);
+        }
+        give_gamepad(&mut bindings, gamepad);
+        *input_map = bindings;
+    }
+}
+
+/// Points `bindings` at `gamepad`, or drops their gamepad bindings if there
+/// isn't one.
+pub fn give_gamepad(bindings: &mut InputMap<Action>, gamepad: Option<Gamepad>) {
+    match gamepad {
+        Some(gamepad) => {
+            bindings.set_gamepad(gamepad);
+        }
+        None => {
+            for action in Action::variants() {
+                if let Some(inputs) = bindings.get_mut(action) {
+                    inputs.retain(|input| !is_gamepad_input(input));
+                }
+            }
+            bindings.clear_gamepad();
+        }
+    }
+}
+
+/// Every player slot's controls, as set on the controls screen.
+#[derive(Resource, Clone, Debug)]
+pub struct Bindings(pub Vec<InputMap<Action>>);
+
+impl Default for Bindings {
+    fn default() -> Self {
+        Self((0..MAX_PLAYERS).map(default_input_map).collect())
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum BindingsError {
+    #[error("no config directory")]
+    NoConfigDir,
+    #[error("could not access controls file: {0}")]
+    Io(#[from] io::Error),
+    #[error("could not parse controls file: {0}")]
+    Parse(#[from] ron::error::SpannedError),
+    #[error("could not write controls file: {0}")]
+    Write(#[from] ron::Error),
+}
+
+impl Bindings {
+    pub fn get(&self, player: usize) -> InputMap<Action> {
+        self.0
+            .get(player)
+            .cloned()
+            .unwrap_or_else(|| default_input_map(player))
+    }
+
+    pub fn load() -> Result<Self, BindingsError> {
+        let path = bindings_path().ok_or(BindingsError::NoConfigDir)?;
+        let mut players: Vec<InputMap<Action>> = ron::from_str(&fs::read_to_string(path)?)?;
+        // an older file might not have every slot
+        players.extend((players.len()..MAX_PLAYERS).map(default_input_map));
+        Ok(Self(players))
+    }
+
+    /// Every key bound for more than one player slot.
+    pub fn keyboard_conflicts(&self) -> Vec<KeyConflict> {
+        keyboard_conflicts(self.0.iter().enumerate())
+    }
+
+    /// Takes every conflicting key away from all but the first slot that has
+    /// it, the same way [`Roster::resolve_conflicts`] does for a match.
+    pub fn resolve_conflicts(&mut self) -> Vec<KeyConflict> {
+        let conflicts = self.keyboard_conflicts();
+        let mut input_maps: Vec<Option<&mut InputMap<Action>>> =
+            self.0.iter_mut().map(Some).collect();
+        resolve_conflicts(&conflicts, |player| input_maps[player].take());
+        conflicts
+    }
+
+    pub fn save(&self) -> Result<(), BindingsError> {
+        let path = bindings_path().ok_or(BindingsError::NoConfigDir)?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let text = ron::ser::to_string_pretty(&self.0, ron::ser::PrettyConfig::default())?;
+        fs::write(path, text)?;
+        Ok(())
+    }
+}
+
+/// Where the controls are saved, in the usual config folder for the os.
+fn bindings_path() -> Option<PathBuf> {
+    let home = || std::env::var_os("HOME").map(PathBuf::from);
+    let dir = if cfg!(target_os = "windows") {
+        std::env::var_os("APPDATA").map(PathBuf::from)
+    } else if cfg!(target_os = "macos") {
+        home().map(|home| home.join("Library/Application Support"))
+    } else {
+        std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| home().map(|home| home.join(".config")))
+    }?;
+    Some(dir.join("ppan").join("controls.ron"))
+}
+
+pub fn load_bindings(mut commands: Commands) {
+    let bindings = Bindings::load().unwrap_or_else(|error| {
+        info!("using default controls: {error}");
+        Bindings::default()
+    });
+    commands.insert_resource(bindings);
+}
+
+pub fn save_bindings(mut bindings: ResMut<Bindings>) {
+    for conflict in bindings.resolve_conflicts() {
+        warn!(
+            "{:?} is bound for players {:?}",
+            conflict.key, conflict.players
+        );
+    }
+    if let Err(error) = bindings.save() {
+        warn!("{error}");
+    }
+}
+
+/// A paddle steered towards the mouse cursor.
+#[derive(Component)]
+pub struct MouseControl;
+
+/// A paddle whose actions come over the network rather than its own
+/// bindings.
+#[derive(Component)]
+pub struct RemoteInput;
+
+/// how far from the cursor (in pixels) a paddle pushes at full strength
+const MOUSE_RANGE: f32 = 100.0;
+/// how far ahead a mouse-controlled paddle looks when deciding to brake
+const MOUSE_LOOKAHEAD: f32 = 0.2;
+
+/// Points [`Action::Move`] at the cursor for [`MouseControl`] paddles, so they
+/// get the same acceleration and damping as a stick.
+pub fn mouse_control(
+    window_query: Query<&Window, With<PrimaryWindow>>,
+    camera_query: Query<(&Camera, &GlobalTransform)>,
+    mut paddle_query: Query<(&mut ActionState<Action>, &Transform, &Velocity), With<MouseControl>>,
+) {
+    let (Ok(window), Ok((camera, camera_transform))) =
+        (window_query.get_single(), camera_query.get_single())
+    else {
+        return;
+    };
+    let Some(cursor) = window
+        .cursor_position()
+        .and_then(|cursor| camera.viewport_to_world_2d(camera_transform, cursor))
+    else {
+        return;
+    };
+    for (mut action_state, transform, vel) in &mut paddle_query {
+        let position = transform.translation.truncate();
+        // aim for where the paddle's heading, so it slows down before it gets there
+        let diff = cursor - (position + vel.linvel * MOUSE_LOOKAHEAD);
+        let push = (diff / MOUSE_RANGE).clamp_length_max(1.0);
+        action_state.action_data_mut(Action::Move).axis_pair = Some(DualAxisData::from_xy(push));
+    }
+}
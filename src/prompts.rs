@@ -0,0 +1,360 @@
+use bevy::{
+    ecs::system::SystemParam,
+    input::gamepad::{GamepadAxisChangedEvent, GamepadButtonChangedEvent},
+    prelude::*,
+};
+use leafwing_input_manager::{axislike::AxisType, prelude::*};
+
+use crate::{
+    match_setup::MAX_PLAYERS,
+    player::{Bindings, Player},
+    Action,
+};
+
+/// The kinds of controller there's prompt art for.
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InputDevice {
+    #[default]
+    Keyboard,
+    Xbox,
+    PlayStation,
+    Switch,
+    SteamDeck,
+}
+
+impl InputDevice {
+    /// Guesses the kind of controller from the name the os gives it. Anything
+    /// unrecognised gets Xbox prompts, since most pads copy that layout.
+    pub fn from_gamepad_name(name: &str) -> Self {
+        let name = name.to_lowercase();
+        let has = |words: &[&str]| words.iter().any(|word| name.contains(word));
+        if has(&["steam deck"]) {
+            Self::SteamDeck
+        } else if has(&[
+            "playstation",
+            "dualsense",
+            "dualshock",
+            "ps4",
+            "ps5",
+            "sony",
+        ]) {
+            Self::PlayStation
+        } else if has(&["nintendo", "switch", "joy-con", "pro controller"]) {
+            Self::Switch
+        } else {
+            Self::Xbox
+        }
+    }
+
+    /// folder and file name prefix under `assets/xelu_prompts`
+    const fn folder(self) -> (&'static str, &'static str) {
+        match self {
+            Self::Keyboard => ("Keyboard & Mouse/Dark", ""),
+            Self::Xbox => ("Xbox Series", "XboxSeriesX"),
+            Self::PlayStation => ("PS5", "PS5"),
+            Self::Switch => ("Switch", "Switch"),
+            Self::SteamDeck => ("Steam Deck", "SteamDeck"),
+        }
+    }
+
+    fn button_name(self, button: GamepadButtonType) -> Option<&'static str> {
+        use GamepadButtonType::*;
+        Some(match (self, button) {
+            (_, DPadUp) => "Dpad_Up",
+            (_, DPadDown) => "Dpad_Down",
+            (_, DPadLeft) => "Dpad_Left",
+            (_, DPadRight) => "Dpad_Right",
+            // the switch set has no stick click images, only the sticks
+            (Self::Switch, LeftThumb) => "Left_Stick",
+            (Self::Switch, RightThumb) => "Right_Stick",
+            (_, LeftThumb) => "Left_Stick_Click",
+            (_, RightThumb) => "Right_Stick_Click",
+
+            (Self::PlayStation, South) => "Cross",
+            (Self::PlayStation, East) => "Circle",
+            (Self::PlayStation, West) => "Square",
+            (Self::PlayStation, North) => "Triangle",
+            (Self::PlayStation | Self::SteamDeck, LeftTrigger) => "L1",
+            (Self::PlayStation | Self::SteamDeck, RightTrigger) => "R1",
+            (Self::PlayStation | Self::SteamDeck, LeftTrigger2) => "L2",
+            (Self::PlayStation | Self::SteamDeck, RightTrigger2) => "R2",
+            (Self::PlayStation, Start) => "Options",
+            (Self::PlayStation, Select) => "Share",
+
+            // nintendo swaps the face buttons round
+            (Self::Switch, South) => "B",
+            (Self::Switch, East) => "A",
+            (Self::Switch, West) => "Y",
+            (Self::Switch, North) => "X",
+            (Self::Switch, Start) => "Plus",
+            (Self::Switch, Select) => "Minus",
+
+            (_, South) => "A",
+            (_, East) => "B",
+            (_, West) => "X",
+            (_, North) => "Y",
+            (_, LeftTrigger) => "LB",
+            (_, RightTrigger) => "RB",
+            (_, LeftTrigger2) => "LT",
+            (_, RightTrigger2) => "RT",
+            (Self::SteamDeck, Select) => "Square",
+            (_, Start) => "Menu",
+            (_, Select) => "View",
+            _ => return None,
+        })
+    }
+
+    /// Which image shows `input` on this device, if there is one.
+    fn glyph_path(self, input: &UserInput) -> Option<String> {
+        let raw = input.raw_inputs();
+        let (folder, prefix) = self.folder();
+        if self == Self::Keyboard {
+            let name = key_name(*raw.keycodes.first()?)?;
+            return Some(format!("xelu_prompts/{folder}/{name}_Key_Dark.png"));
+        }
+        let name = match (raw.gamepad_buttons.first(), raw.axis_data.first()) {
+            (Some(button), _) => self.button_name(*button)?,
+            (None, Some((AxisType::Gamepad(axis), _))) => match axis {
+                GamepadAxisType::LeftStickX | GamepadAxisType::LeftStickY => "Left_Stick",
+                GamepadAxisType::RightStickX | GamepadAxisType::RightStickY => "Right_Stick",
+                _ => return None,
+            },
+            _ => return None,
+        };
+        Some(format!("xelu_prompts/{folder}/{prefix}_{name}.png"))
+    }
+}
+
+fn key_name(key: KeyCode) -> Option<String> {
+    use KeyCode::*;
+    let name = match key {
+        Key0 | Numpad0 => "0",
+        Key1 | Numpad1 => "1",
+        Key2 | Numpad2 => "2",
+        Key3 | Numpad3 => "3",
+        Key4 | Numpad4 => "4",
+        Key5 | Numpad5 => "5",
+        Key6 | Numpad6 => "6",
+        Key7 | Numpad7 => "7",
+        Key8 | Numpad8 => "8",
+        Key9 | Numpad9 => "9",
+        Left => "Arrow_Left",
+        Right => "Arrow_Right",
+        Up => "Arrow_Up",
+        Down => "Arrow_Down",
+        Escape => "Esc",
+        Return | NumpadEnter => "Enter",
+        Back => "Backspace",
+        Delete => "Del",
+        Space => "Space",
+        Tab => "Tab",
+        Insert => "Insert",
+        Home => "Home",
+        End => "End",
+        PageUp => "Page_Up",
+        PageDown => "Page_Down",
+        ShiftLeft | ShiftRight => "Shift",
+        ControlLeft | ControlRight => "Ctrl",
+        AltLeft | AltRight => "Alt",
+        Comma => "Mark_Left",
+        Period => "Mark_Right",
+        Minus | NumpadSubtract => "Minus",
+        Equals | Plus | NumpadAdd => "Plus",
+        Asterisk | NumpadMultiply => "Asterisk",
+        Slash | NumpadDivide => "Slash",
+        Semicolon => "Semicolon",
+        Apostrophe => "Quote",
+        Grave => "Tilda",
+        BracketLeft => "Bracket_Left",
+        BracketRight => "Bracket_Right",
+        // letters and function keys are named the same as the files
+        A | B | C | D | E | F | G | H | I | J | K | L | M | N | O | P | Q | R | S | T | U | V
+        | W | X | Y | Z | F1 | F2 | F3 | F4 | F5 | F6 | F7 | F8 | F9 | F10 | F11 | F12 => {
+            return Some(format!("{key:?}"));
+        }
+        _ => return None,
+    };
+    Some(name.to_string())
+}
+
+/// The device each player slot last used.
+#[derive(Resource, Default, Clone, PartialEq, Eq, Debug)]
+pub struct ActiveDevices(pub [InputDevice; MAX_PLAYERS]);
+
+/// An image showing what to press for `action`, kept up to date with
+/// `player`'s bindings and device.
+#[derive(Component)]
+pub struct PromptGlyph {
+    pub player: usize,
+    pub action: Action,
+}
+
+/// Looks up prompt images for actions.
+#[derive(SystemParam)]
+pub struct Prompts<'w> {
+    server: Res<'w, AssetServer>,
+    devices: Res<'w, ActiveDevices>,
+    bindings: Res<'w, Bindings>,
+}
+
+impl Prompts<'_> {
+    /// The image for `player`'s binding of `action` on the device they're
+    /// currently using.
+    pub fn glyph(&self, player: usize, action: Action) -> Option<Handle<Image>> {
+        let device = self.devices.0.get(player).copied().unwrap_or_default();
+        let input_map = self.bindings.get(player);
+        let path = input_map
+            .get(action)?
+            .iter()
+            .find_map(|input| device.glyph_path(input))?;
+        Some(self.server.load(path))
+    }
+
+    fn is_changed(&self) -> bool {
+        self.devices.is_changed() || self.bindings.is_changed()
+    }
+}
+
+/// A small image slot for a [`PromptGlyph`], to go inline with text.
+pub fn prompt_glyph_bundle(player: usize, action: Action) -> impl Bundle {
+    (
+        ImageBundle {
+            style: Style {
+                width: Val::Px(40.0),
+                height: Val::Px(40.0),
+                ..default()
+            },
+            ..default()
+        },
+        PromptGlyph { player, action },
+    )
+}
+
+/// Works out which device each player is using from the last thing they
+/// pressed. Keys count for every player they're bound for. Gamepads count
+/// for the paddle they're assigned to, or outside a match for the player
+/// slot matching their connection order.
+pub fn detect_input_device(
+    keys: Res<Input<KeyCode>>,
+    mut button_events: EventReader<GamepadButtonChangedEvent>,
+    mut axis_events: EventReader<GamepadAxisChangedEvent>,
+    gamepads: Res<Gamepads>,
+    bindings: Res<Bindings>,
+    paddle_query: Query<(&Player, &InputMap<Action>)>,
+    mut devices: ResMut<ActiveDevices>,
+) {
+    let mut active = devices.clone();
+
+    for key in keys.get_just_pressed() {
+        for (player, input_map) in bindings.0.iter().enumerate().take(MAX_PLAYERS) {
+            let bound = input_map.iter().any(|(_, inputs)| {
+                inputs
+                    .iter()
+                    .any(|input| input.raw_inputs().keycodes.contains(key))
+            });
+            if bound {
+                active.0[player] = InputDevice::Keyboard;
+            }
+        }
+    }
+
+    let mut connected: Vec<Gamepad> = gamepads.iter().collect();
+    connected.sort_by_key(|gamepad| gamepad.id);
+    let used = button_events
+        .read()
+        .filter(|event| event.value > 0.5)
+        .map(|event| event.gamepad)
+        .chain(
+            axis_events
+                .read()
+                .filter(|event| event.value.abs() > 0.5)
+                .map(|event| event.gamepad),
+        );
+    for gamepad in used {
+        let player = if paddle_query.is_empty() {
+            connected.iter().position(|g| *g == gamepad)
+        } else {
+            paddle_query
+                .iter()
+                .find(|(_, input_map)| input_map.gamepad() == Some(gamepad))
+                .map(|(player, _)| player.0)
+        };
+        let Some(slot) = player.and_then(|player| active.0.get_mut(player)) else {
+            continue;
+        };
+        *slot = gamepads
+            .name(gamepad)
+            .map_or(InputDevice::Xbox, InputDevice::from_gamepad_name);
+    }
+
+    devices.set_if_neq(active);
+}
+
+/// Swaps every [`PromptGlyph`] over when its player's device or bindings
+/// change.
+pub fn update_prompt_glyphs(
+    prompts: Prompts,
+    mut query: Query<(Ref<PromptGlyph>, &mut UiImage, &mut Visibility)>,
+) {
+    let changed = prompts.is_changed();
+    for (prompt, mut image, mut visibility) in &mut query {
+        if !changed && !prompt.is_added() {
+            continue;
+        }
+        match prompts.glyph(prompt.player, prompt.action) {
+            Some(texture) => {
+                image.texture = texture;
+                *visibility = Visibility::Inherited;
+            }
+            None => *visibility = Visibility::Hidden,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use leafwing_input_manager::user_input::InputKind;
+
+    use super::*;
+
+    #[test]
+    fn every_gamepad_glyph_exists() {
+        use GamepadButtonType::*;
+        let buttons = [
+            South,
+            East,
+            North,
+            West,
+            LeftTrigger,
+            LeftTrigger2,
+            RightTrigger,
+            RightTrigger2,
+            Select,
+            Start,
+            LeftThumb,
+            RightThumb,
+            DPadUp,
+            DPadDown,
+            DPadLeft,
+            DPadRight,
+        ];
+        let devices = [
+            InputDevice::Xbox,
+            InputDevice::PlayStation,
+            InputDevice::Switch,
+            InputDevice::SteamDeck,
+        ];
+        for device in devices {
+            for button in buttons {
+                let input = UserInput::from(InputKind::GamepadButton(button));
+                let path = device.glyph_path(&input).unwrap();
+                assert!(
+                    Path::new("assets").join(&path).exists(),
+                    "{device:?} {button:?}: {path}"
+                );
+            }
+        }
+    }
+}
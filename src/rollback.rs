@@ -0,0 +1,727 @@
+//! Online 1v1 with rollback. Each peer runs the whole match and only sends
+//! its own inputs over UDP. Until the other player's input for a tick
+//! arrives it's guessed to be the same as their last one. When a guess turns
+//! out wrong, the match is put back to that tick and played forward again
+//! with the real input.
+//!
+//! Restoring only covers the gameplay state listed in [`Snapshot`]. Rapier's
+//! own solver state isn't saved, so a resimulated tick can come out slightly
+//! different from the original. [`ChecksumPlugin`](crate::checksum::ChecksumPlugin)
+//! on both peers shows if that ever adds up to a desync.
+
+use std::{
+    collections::VecDeque,
+    io,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+};
+
+use bevy::prelude::*;
+use bevy_rapier2d::prelude::Velocity;
+use leafwing_input_manager::prelude::*;
+
+use crate::{
+    checksum::Checksums,
+    game::{PaddleContacts, RallySpeed, Score, Serve},
+    net::{take_over_paddles, NetInput},
+    player::Player,
+    rules::{MatchProgress, MatchResult},
+    Action,
+    AppState,
+    Ball,
+    GameTick,
+    NextStop,
+    Paddle,
+    QueuedRotation,
+    Rotating,
+    RotatingM,
+    RotationQueue,
+    RotationVelocity,
+};
+
+/// Local inputs are applied this many ticks after they're read, so they
+/// usually reach the other peer before it needs them.
+pub const INPUT_DELAY: u32 = 2;
+/// The furthest a peer runs ahead of the last input it's had from the
+/// other one before it waits.
+pub const MAX_PREDICTION: u32 = 8;
+
+/// first bytes of every packet, so stray traffic is ignored
+const MAGIC: [u8; 2] = *b"PP";
+/// so a long stall doesn't make a packet too big to send
+const MAX_INPUTS_PER_PACKET: usize = 128;
+
+/// Runs the match through the [`RollbackSession`], when there is one.
+pub struct RollbackPlugin;
+
+impl Plugin for RollbackPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            FixedUpdate,
+            rollback_update.run_if(resource_exists::<RollbackSession>()),
+        );
+    }
+}
+
+/// Marks the paddles and ball the session saves, restores and feeds input
+/// to.
+#[derive(Component)]
+pub struct Rollback;
+
+/// What the peers send each other every tick.
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct Packet {
+    /// how many of the receiver's inputs the sender has, so it knows where
+    /// to resend from
+    ack: u32,
+    /// the tick `inputs` starts at
+    start: u32,
+    inputs: Vec<NetInput>,
+}
+
+impl Packet {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(11 + self.inputs.len() * NetInput::SIZE);
+        bytes.extend(MAGIC);
+        bytes.extend(self.ack.to_le_bytes());
+        bytes.extend(self.start.to_le_bytes());
+        bytes.push(self.inputs.len() as u8);
+        for input in &self.inputs {
+            bytes.extend(input.to_bytes());
+        }
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let (header, inputs) = bytes.split_at_checked(11)?;
+        if header[..2] != MAGIC {
+            return None;
+        }
+        let ack = u32::from_le_bytes(header[2..6].try_into().ok()?);
+        let start = u32::from_le_bytes(header[6..10].try_into().ok()?);
+        let count = usize::from(header[10]);
+        if inputs.len() != count * NetInput::SIZE {
+            return None;
+        }
+        Some(Self {
+            ack,
+            start,
+            inputs: inputs
+                .chunks_exact(NetInput::SIZE)
+                .map(NetInput::from_bytes)
+                .collect(),
+        })
+    }
+}
+
+/// A paddle's or the ball's state at the start of a tick.
+#[derive(Clone)]
+struct BodySnapshot {
+    entity: Entity,
+    transform: Transform,
+    velocity: Velocity,
+    next_stop: Option<f32>,
+    rotating: Option<RotatingM>,
+    rotation_velocity: Option<f32>,
+    queue: Option<VecDeque<QueuedRotation>>,
+    rally: Option<f32>,
+    contacts: Option<Vec<Entity>>,
+}
+
+/// Everything needed to play a tick again from its start.
+#[derive(Clone)]
+struct Snapshot {
+    bodies: Vec<BodySnapshot>,
+    score: Option<Score>,
+    serve: Option<Serve>,
+    progress: Option<MatchProgress>,
+    result: Option<MatchResult>,
+    next_state: Option<AppState>,
+}
+
+type SnapshotQuery<'a> = (
+    Entity,
+    &'a Transform,
+    &'a Velocity,
+    Option<&'a NextStop>,
+    Option<&'a Rotating>,
+    Option<&'a RotationVelocity>,
+    Option<&'a RotationQueue>,
+    Option<&'a RallySpeed>,
+    Option<&'a PaddleContacts>,
+);
+
+type RestoreQuery<'a> = (
+    &'a mut Transform,
+    &'a mut Velocity,
+    Option<&'a mut NextStop>,
+    Option<&'a mut Rotating>,
+    Option<&'a mut RotationVelocity>,
+    Option<&'a mut RotationQueue>,
+    Option<&'a mut RallySpeed>,
+    Option<&'a mut PaddleContacts>,
+);
+
+impl Snapshot {
+    fn save(world: &mut World) -> Self {
+        let mut query = world.query_filtered::<SnapshotQuery, With<Rollback>>();
+        let bodies = query
+            .iter(world)
+            .map(
+                |(
+                    entity,
+                    transform,
+                    velocity,
+                    next_stop,
+                    rotating,
+                    spin,
+                    queue,
+                    rally,
+                    contacts,
+                )| {
+                    BodySnapshot {
+                        entity,
+                        transform: *transform,
+                        velocity: *velocity,
+                        next_stop: next_stop.map(|next_stop| next_stop.0),
+                        rotating: rotating.map(|rotating| rotating.0),
+                        rotation_velocity: spin.map(|spin| spin.0),
+                        queue: queue.map(|queue| queue.0.clone()),
+                        rally: rally.map(|rally| rally.0),
+                        contacts: contacts.map(|contacts| contacts.0.clone()),
+                    }
+                },
+            )
+            .collect();
+        Self {
+            bodies,
+            score: world.get_resource::<Score>().copied(),
+            serve: world.get_resource::<Serve>().cloned(),
+            progress: world.get_resource::<MatchProgress>().cloned(),
+            result: world.get_resource::<MatchResult>().copied(),
+            next_state: world.resource::<NextState<AppState>>().0,
+        }
+    }
+
+    fn restore(&self, world: &mut World) {
+        let mut query = world.query_filtered::<RestoreQuery, With<Rollback>>();
+        for body in &self.bodies {
+            let Ok((
+                mut transform,
+                mut velocity,
+                next_stop,
+                rotating,
+                spin,
+                queue,
+                rally,
+                contacts,
+            )) = query.get_mut(world, body.entity)
+            else {
+                continue;
+            };
+            *transform = body.transform;
+            *velocity = body.velocity;
+            if let (Some(mut next_stop), Some(saved)) = (next_stop, body.next_stop) {
+                next_stop.0 = saved;
+            }
+            if let (Some(mut rotating), Some(saved)) = (rotating, body.rotating) {
+                rotating.0 = saved;
+            }
+            if let (Some(mut spin), Some(saved)) = (spin, body.rotation_velocity) {
+                spin.0 = saved;
+            }
+            if let (Some(mut queue), Some(saved)) = (queue, &body.queue) {
+                queue.0.clone_from(saved);
+            }
+            if let (Some(mut rally), Some(saved)) = (rally, body.rally) {
+                rally.0 = saved;
+            }
+            if let (Some(mut contacts), Some(saved)) = (contacts, &body.contacts) {
+                contacts.0.clone_from(saved);
+            }
+        }
+        // written in place, so nothing sees them as newly added
+        if let (Some(mut score), Some(saved)) = (world.get_resource_mut::<Score>(), self.score) {
+            *score = saved;
+        }
+        if let Some(saved) = &self.serve {
+            world.insert_resource(saved.clone());
+        }
+        if let (Some(mut progress), Some(saved)) =
+            (world.get_resource_mut::<MatchProgress>(), &self.progress)
+        {
+            *progress = saved.clone();
+        }
+        // a match end that was only guessed is taken back
+        match self.result {
+            Some(saved) => world.insert_resource(saved),
+            None => {
+                world.remove_resource::<MatchResult>();
+            }
+        }
+        world.resource_mut::<NextState<AppState>>().0 = self.next_state;
+    }
+}
+
+/// An online match against one other peer.
+#[derive(Resource)]
+pub struct RollbackSession {
+    socket: UdpSocket,
+    peer: SocketAddr,
+    local_player: usize,
+    /// the next tick to be played
+    frame: u32,
+    /// indexed by tick, already [`INPUT_DELAY`] ahead of `frame`
+    local_inputs: Vec<NetInput>,
+    /// every input received from the peer so far, with no gaps
+    remote_inputs: Vec<NetInput>,
+    /// the peer input each tick was last played with, real or guessed
+    used_remote: Vec<NetInput>,
+    /// how many of our inputs the peer has confirmed
+    peer_ack: u32,
+    /// the earliest tick played with a wrong guess
+    rollback_to: Option<u32>,
+    snapshots: VecDeque<(u32, Snapshot)>,
+    /// how many times the match has been rewound, for diagnostics
+    pub rollbacks: u32,
+}
+
+impl RollbackSession {
+    /// Listens on `local` and plays against whoever's at `peer`, as
+    /// `local_player` (0 or 1).
+    pub fn bind(
+        local: impl ToSocketAddrs,
+        peer: SocketAddr,
+        local_player: usize,
+    ) -> io::Result<Self> {
+        Self::new(UdpSocket::bind(local)?, peer, local_player)
+    }
+
+    pub fn new(socket: UdpSocket, peer: SocketAddr, local_player: usize) -> io::Result<Self> {
+        if local_player > 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "online matches are 1v1, so the player is 0 or 1",
+            ));
+        }
+        socket.set_nonblocking(true)?;
+        // the first few ticks are played before anyone's input can count
+        let delay = vec![NetInput::default(); INPUT_DELAY as usize];
+        Ok(Self {
+            socket,
+            peer,
+            local_player,
+            frame: 0,
+            local_inputs: delay.clone(),
+            remote_inputs: delay,
+            used_remote: Vec::new(),
+            peer_ack: INPUT_DELAY,
+            rollback_to: None,
+            snapshots: VecDeque::new(),
+            rollbacks: 0,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub const fn local_player(&self) -> usize {
+        self.local_player
+    }
+
+    /// The next tick to be played.
+    pub const fn frame(&self) -> u32 {
+        self.frame
+    }
+
+    /// Every tick before this has been played with the peer's real input,
+    /// once any pending rollback has happened.
+    pub fn confirmed_frame(&self) -> u32 {
+        self.remote_inputs.len() as u32
+    }
+
+    /// `player`'s input for `frame`: real if we have it, otherwise a guess
+    /// that they're still doing what they last did.
+    fn input(&self, player: usize, frame: u32) -> NetInput {
+        let frame = frame as usize;
+        if player == self.local_player {
+            return self.local_inputs.get(frame).copied().unwrap_or_default();
+        }
+        self.remote_inputs
+            .get(frame)
+            .or_else(|| self.remote_inputs.last())
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn receive(&mut self) {
+        let mut buffer = [0; 1024];
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                // e.g. the peer's port isn't open yet
+                Err(e) if e.kind() == io::ErrorKind::ConnectionReset => continue,
+                Err(e) => {
+                    warn!("couldn't receive from peer: {e}");
+                    break;
+                }
+            };
+            if from != self.peer {
+                continue;
+            }
+            let Some(packet) = Packet::decode(&buffer[..len]) else {
+                continue;
+            };
+            self.peer_ack = self.peer_ack.max(packet.ack);
+            for (frame, input) in (packet.start..).zip(packet.inputs) {
+                let next = self.remote_inputs.len() as u32;
+                if frame < next {
+                    continue;
+                }
+                if frame > next {
+                    // something went missing; it'll be sent again
+                    break;
+                }
+                self.remote_inputs.push(input);
+                let guessed = self.used_remote.get(frame as usize);
+                if guessed.is_some_and(|guessed| *guessed != input) {
+                    let from = self.rollback_to.map_or(frame, |from| from.min(frame));
+                    self.rollback_to = Some(from);
+                }
+            }
+        }
+    }
+
+    /// Sends every local input the peer hasn't confirmed yet.
+    fn send(&self) {
+        let start = self.peer_ack.min(self.local_inputs.len() as u32);
+        let inputs: Vec<NetInput> = self.local_inputs[start as usize..]
+            .iter()
+            .take(MAX_INPUTS_PER_PACKET)
+            .copied()
+            .collect();
+        let packet = Packet {
+            ack: self.remote_inputs.len() as u32,
+            start,
+            inputs,
+        };
+        match self.socket.send_to(&packet.encode(), self.peer) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => warn!("couldn't send to peer: {e}"),
+        }
+    }
+
+    /// Sets both paddles' actions for `frame`, and queues any rotate presses
+    /// that start on it.
+    fn apply_inputs(&mut self, world: &mut World, frame: u32) {
+        let remote = self.input(1 - self.local_player, frame);
+        if let Some(used) = self.used_remote.get_mut(frame as usize) {
+            *used = remote;
+        } else {
+            self.used_remote.push(remote);
+        }
+
+        let mut query = world.query_filtered::<(
+            &Player,
+            &mut ActionState<Action>,
+            &mut RotationQueue,
+        ), With<Rollback>>();
+        for (player, mut action_state, mut queue) in query.iter_mut(world) {
+            let input = self.input(player.0, frame);
+            let previous = frame
+                .checked_sub(1)
+                .map(|previous| self.input(player.0, previous))
+                .unwrap_or_default();
+            input.apply(&mut action_state, &mut queue, previous);
+        }
+    }
+
+    /// Saves the state, then plays `frame`.
+    fn run_frame(&mut self, world: &mut World, frame: u32) {
+        self.snapshots.push_back((frame, Snapshot::save(world)));
+        while self.snapshots.len() > MAX_PREDICTION as usize + 2 {
+            self.snapshots.pop_front();
+        }
+        self.apply_inputs(world, frame);
+        if let Some(mut checksums) = world.get_resource_mut::<Checksums>() {
+            checksums.tick = u64::from(frame);
+        }
+        world.run_schedule(GameTick);
+    }
+
+    /// Puts the match back to the start of `from` and plays it forward to
+    /// where it was.
+    fn roll_back(&mut self, world: &mut World, from: u32) {
+        let Some(index) = self.snapshots.iter().position(|(frame, _)| *frame == from) else {
+            error!("no snapshot for tick {from}, can't roll back");
+            return;
+        };
+        let (_, snapshot) = self.snapshots[index].clone();
+        self.snapshots.truncate(index);
+        snapshot.restore(world);
+        for frame in from..self.frame {
+            self.run_frame(world, frame);
+        }
+        self.rollbacks += 1;
+    }
+
+    /// Lets the match end only once every tick up to it has been played with
+    /// the peer's real input, since a rollback can't undo leaving the match.
+    fn hold_match_end(&self, world: &mut World) {
+        if world.contains_resource::<MatchResult>() {
+            let confirmed = self.frame <= self.confirmed_frame();
+            world.resource_mut::<NextState<AppState>>().0 =
+                confirmed.then_some(AppState::MatchOver);
+        }
+    }
+
+    /// Takes over the paddles and ball of a newly started match.
+    fn attach(world: &mut World, local_player: usize) {
+        if !take_over_paddles(world, Some(local_player)) {
+            return;
+        }
+        let mut bodies =
+            world.query_filtered::<Entity, (Or<(With<Paddle>, With<Ball>)>, Without<Rollback>)>();
+        for entity in bodies.iter(world).collect::<Vec<_>>() {
+            world.entity_mut(entity).insert(Rollback);
+        }
+    }
+}
+
+/// Plays one tick of the online match: swaps inputs with the peer, rolls
+/// back if a guess was wrong, then plays the next tick, unless the peer has
+/// fallen too far behind or the match has ended.
+fn rollback_update(world: &mut World) {
+    if *world.resource::<State<AppState>>().get() != AppState::InGame {
+        // nothing to sync, but the tick still has to run for interpolation
+        world.run_schedule(GameTick);
+        return;
+    }
+    world.resource_scope(|world, mut session: Mut<RollbackSession>| {
+        RollbackSession::attach(world, session.local_player);
+        session.receive();
+        if world.contains_resource::<MatchResult>() {
+            // the end may have been played with a guess, so wait for it to
+            // be confirmed or taken back
+            if let Some(from) = session.rollback_to.take() {
+                session.roll_back(world, from);
+            }
+            session.send();
+            session.hold_match_end(world);
+            return;
+        }
+        if session.frame >= session.confirmed_frame() + MAX_PREDICTION {
+            // let the peer catch up
+            session.send();
+            return;
+        }
+
+        let local = world
+            .get_resource::<ActionState<Action>>()
+            .map(NetInput::from_action_state)
+            .unwrap_or_default();
+        session.local_inputs.push(local);
+        session.send();
+
+        if let Some(from) = session.rollback_to.take() {
+            session.roll_back(world, from);
+        }
+        let frame = session.frame;
+        session.run_frame(world, frame);
+        session.frame += 1;
+        session.hold_match_end(world);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        checksum::ChecksumPlugin,
+        headless::{headless_app, start_match},
+        match_setup::MatchConfig,
+        rules::MatchRules,
+    };
+
+    #[test]
+    fn packets_round_trip() {
+        let packet = Packet {
+            ack: 12,
+            start: 40,
+            inputs: vec![
+                NetInput {
+                    buttons: 0b10_0101,
+                    stick: [-127, 64],
+                },
+                NetInput::default(),
+            ],
+        };
+        assert_eq!(Packet::decode(&packet.encode()), Some(packet));
+        assert_eq!(Packet::decode(b"PPnot a packet"), None);
+        assert_eq!(Packet::decode(b"XX"), None);
+    }
+
+    /// Two peers on loopback, each playing one paddle.
+    fn peers() -> (App, App) {
+        let a_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let b_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (a_addr, b_addr) = (
+            a_socket.local_addr().unwrap(),
+            b_socket.local_addr().unwrap(),
+        );
+        let app = |socket, peer, player| {
+            let mut app = headless_app(MatchConfig::default());
+            app.add_plugins((RollbackPlugin, ChecksumPlugin::default()))
+                .insert_resource(RollbackSession::new(socket, peer, player).unwrap());
+            start_match(&mut app);
+            // tests press the actions themselves
+            app.world.remove_resource::<InputMap<Action>>();
+            app
+        };
+        (app(a_socket, b_addr, 0), app(b_socket, a_addr, 1))
+    }
+
+    fn session(app: &App) -> &RollbackSession {
+        app.world.resource::<RollbackSession>()
+    }
+
+    /// Holds each action in `script` for the ticks in its ranges.
+    fn step(app: &mut App, script: &[(Action, std::ops::Range<u32>)]) {
+        let frame = session(app).frame();
+        let mut action_state = app.world.resource_mut::<ActionState<Action>>();
+        for (action, _) in script {
+            action_state.release(*action);
+        }
+        // input is read INPUT_DELAY ticks before it's played
+        for (action, _) in script
+            .iter()
+            .filter(|(_, ticks)| ticks.contains(&(frame + INPUT_DELAY)))
+        {
+            action_state.press(*action);
+        }
+        app.update();
+    }
+
+    /// Plays both peers to `end`, with b lagging up to `lag` ticks behind so
+    /// a has to guess b's inputs and correct itself.
+    fn play(
+        a: &mut App,
+        b: &mut App,
+        a_script: &[(Action, std::ops::Range<u32>)],
+        b_script: &[(Action, std::ops::Range<u32>)],
+        lag: u32,
+        end: u32,
+    ) {
+        while session(a).frame() < end || session(b).frame() < end {
+            for _ in 0..lag {
+                if session(a).frame() < end {
+                    step(a, a_script);
+                }
+            }
+            for _ in 0..lag {
+                if session(b).frame() < end {
+                    step(b, b_script);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn waits_for_a_silent_peer() {
+        let (mut a, _b) = peers();
+        for _ in 0..30 {
+            step(&mut a, &[]);
+        }
+        let a = session(&a);
+        assert_eq!(a.frame(), a.confirmed_frame() + MAX_PREDICTION);
+    }
+
+    #[test]
+    fn peers_agree_after_rolling_back() {
+        let (mut a, mut b) = peers();
+        let a_script = [
+            (Action::Up, 10..40),
+            (Action::RotateClockwise, 20..22),
+            (Action::Right, 30..36),
+        ];
+        let b_script = [
+            (Action::Down, 15..50),
+            (Action::RotateAntiClockwise, 25..27),
+            (Action::RotateAntiClockwise, 31..33),
+        ];
+        const END: u32 = 150;
+        play(&mut a, &mut b, &a_script, &b_script, 4, END);
+        assert!(session(&a).rollbacks > 0, "a never had to roll back");
+
+        let a_checksum = a.world.resource::<Checksums>().latest.clone().unwrap();
+        let b_checksum = b.world.resource::<Checksums>().latest.clone().unwrap();
+        assert_eq!(a_checksum.tick, u64::from(END - 1));
+        assert_eq!(a_checksum, b_checksum);
+    }
+
+    #[test]
+    fn peers_agree_on_goals_scored_while_rolling_back() {
+        let (mut a, mut b) = peers();
+        const END: u32 = 600;
+        const WIGGLE_UNTIL: u32 = END - 50;
+        // b heads for the ball, changing its mind every tick on the way, so
+        // every tick a guesses is played again: hits and goals included
+        let mut b_script: Vec<_> = (0..WIGGLE_UNTIL)
+            .map(|tick| {
+                let action = if tick % 2 == 0 {
+                    Action::Up
+                } else {
+                    Action::Down
+                };
+                (action, tick..tick + 1)
+            })
+            .collect();
+        b_script.push((Action::Left, 0..WIGGLE_UNTIL));
+        play(&mut a, &mut b, &[], &b_script, 6, END);
+        assert!(session(&a).rollbacks > 0, "a never had to roll back");
+
+        let score = *a.world.resource::<Score>();
+        assert!(score.left + score.right > 1, "{score:?}");
+        assert_eq!(b.world.resource::<Score>(), &score);
+        let a_checksum = a.world.resource::<Checksums>().latest.clone().unwrap();
+        let b_checksum = b.world.resource::<Checksums>().latest.clone().unwrap();
+        assert_eq!(a_checksum, b_checksum);
+    }
+
+    #[test]
+    fn match_end_waits_for_the_peer() {
+        let (mut a, mut b) = peers();
+        for app in [&mut a, &mut b] {
+            // a draw a few ticks in
+            app.insert_resource(MatchRules {
+                time_limit: Some(std::time::Duration::from_millis(50)),
+                sudden_death: false,
+                ..default()
+            });
+        }
+        for _ in 0..MAX_PREDICTION + 2 {
+            step(&mut a, &[]);
+        }
+        // a has played the end with guessed input, so it could still be
+        // taken back
+        assert!(a.world.contains_resource::<MatchResult>());
+        assert_eq!(
+            *a.world.resource::<State<AppState>>().get(),
+            AppState::InGame
+        );
+
+        for _ in 0..MAX_PREDICTION + 2 {
+            step(&mut b, &[]);
+        }
+        step(&mut a, &[]);
+        step(&mut a, &[]);
+        for app in [&a, &b] {
+            assert_eq!(
+                *app.world.resource::<State<AppState>>().get(),
+                AppState::MatchOver
+            );
+        }
+    }
+}
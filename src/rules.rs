@@ -0,0 +1,303 @@
+use std::time::Duration;
+
+use bevy::prelude::*;
+
+use crate::{
+    client::{ServerConnection, ServerRoom},
+    game::{GameEntity, GoalScored, Score, Side},
+    spawn_menu_button,
+    AppState,
+    MenuButtonPressed,
+    TopLevelNode,
+};
+
+/// How a match is won.
+#[derive(Resource, Reflect, Clone, Debug)]
+#[reflect(Resource)]
+pub struct MatchRules {
+    /// points needed to take a set
+    pub target_score: u32,
+    /// how far ahead you have to be to take a set
+    pub win_by: u32,
+    /// the match ends when this runs out, if there is one
+    pub time_limit: Option<Duration>,
+    /// if time runs out on a tie, play on until the next goal
+    pub sudden_death: bool,
+    /// best-of-N sets
+    pub sets: u32,
+}
+
+impl Default for MatchRules {
+    fn default() -> Self {
+        Self {
+            target_score: 7,
+            win_by: 2,
+            time_limit: None,
+            sudden_death: true,
+            sets: 1,
+        }
+    }
+}
+
+impl MatchRules {
+    /// sets needed to win the match
+    pub const fn sets_to_win(&self) -> u32 {
+        self.sets / 2 + 1
+    }
+}
+
+/// Everything the rules need to remember across sets.
+#[derive(Resource, Default, Clone, Debug)]
+pub struct MatchProgress {
+    pub sets: Score,
+    pub elapsed: Duration,
+    pub sudden_death: bool,
+}
+
+/// How the last match ended. `None` is a draw.
+#[derive(Resource, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MatchResult {
+    pub winner: Option<Side>,
+    pub sets: Score,
+    pub score: Score,
+}
+
+#[derive(Component)]
+pub struct ClockText;
+
+/// Checks the [`MatchRules`] after every goal, and ends the match once
+/// someone has won.
+pub fn evaluate_rules(
+    mut commands: Commands,
+    mut goal_scored: EventReader<GoalScored>,
+    rules: Res<MatchRules>,
+    mut progress: ResMut<MatchProgress>,
+    mut score: ResMut<Score>,
+    mut next_state: ResMut<NextState<AppState>>,
+) {
+    for GoalScored { scorer } in goal_scored.read() {
+        let scorer = *scorer;
+        if progress.sudden_death {
+            end_match(
+                &mut commands,
+                &mut next_state,
+                Some(scorer),
+                &progress,
+                &score,
+            );
+            return;
+        }
+
+        let points = score.get(scorer);
+        let lead = points.saturating_sub(score.get(scorer.opponent()));
+        if points < rules.target_score || lead < rules.win_by {
+            continue;
+        }
+        progress.sets.add(scorer);
+        if progress.sets.get(scorer) >= rules.sets_to_win() {
+            end_match(
+                &mut commands,
+                &mut next_state,
+                Some(scorer),
+                &progress,
+                &score,
+            );
+            return;
+        }
+        // on to the next set
+        *score = Score::default();
+    }
+}
+
+/// Runs the match clock, and decides the match when it runs out.
+pub fn match_clock(
+    mut commands: Commands,
+    time: Res<Time>,
+    rules: Res<MatchRules>,
+    mut progress: ResMut<MatchProgress>,
+    score: Res<Score>,
+    mut next_state: ResMut<NextState<AppState>>,
+    mut text_query: Query<&mut Text, With<ClockText>>,
+) {
+    let Some(limit) = rules.time_limit else {
+        return;
+    };
+    progress.elapsed += time.delta();
+    let remaining = limit.saturating_sub(progress.elapsed);
+    for mut text in &mut text_query {
+        text.sections[0].value = if progress.sudden_death {
+            "sudden death".to_string()
+        } else {
+            let secs = remaining.as_secs();
+            format!("{}:{:02}", secs / 60, secs % 60)
+        };
+    }
+    if !remaining.is_zero() || progress.sudden_death {
+        return;
+    }
+
+    // sets first, then points in the current set
+    let leader = [
+        (progress.sets.left, progress.sets.right),
+        (score.left, score.right),
+    ]
+    .into_iter()
+    .find(|(left, right)| left != right)
+    .map(|(left, right)| {
+        if left > right {
+            Side::Left
+        } else {
+            Side::Right
+        }
+    });
+
+    if leader.is_none() && rules.sudden_death {
+        progress.sudden_death = true;
+    } else {
+        end_match(&mut commands, &mut next_state, leader, &progress, &score);
+    }
+}
+
+fn end_match(
+    commands: &mut Commands,
+    next_state: &mut NextState<AppState>,
+    winner: Option<Side>,
+    progress: &MatchProgress,
+    score: &Score,
+) {
+    commands.insert_resource(MatchResult {
+        winner,
+        sets: progress.sets,
+        score: *score,
+    });
+    next_state.set(AppState::MatchOver);
+}
+
+pub fn spawn_clock(mut commands: Commands, rules: Res<MatchRules>, server: Res<AssetServer>) {
+    if rules.time_limit.is_none() {
+        return;
+    }
+    commands.spawn((
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font: server.load("Blazma/Blazma-Regular.ttf"),
+                font_size: 25.0,
+                color: Color::rgb(0.9, 0.9, 0.9),
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            top: Val::Px(55.0),
+            left: Val::Percent(47.0),
+            ..default()
+        }),
+        ClockText,
+        GameEntity,
+    ));
+}
+
+pub fn match_over_menu(
+    mut commands: Commands,
+    server: Res<AssetServer>,
+    result: Res<MatchResult>,
+    rules: Res<MatchRules>,
+) {
+    let blazma: Handle<Font> = server.load("Blazma/Blazma-Regular.ttf");
+    let headline = match result.winner {
+        Some(Side::Left) => "left wins!",
+        Some(Side::Right) => "right wins!",
+        None => "draw",
+    };
+    let detail = if rules.sets > 1 {
+        format!("sets {} - {}", result.sets.left, result.sets.right)
+    } else {
+        format!("{} - {}", result.score.left, result.score.right)
+    };
+
+    let node = commands
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    width: Val::Percent(100.0),
+                    height: Val::Percent(100.0),
+                    flex_direction: FlexDirection::Column,
+                    align_items: AlignItems::Center,
+                    justify_content: JustifyContent::Center,
+                    ..default()
+                },
+                ..default()
+            },
+            TopLevelNode,
+        ))
+        .id();
+    let buttons = commands
+        .spawn(NodeBundle {
+            style: Style {
+                justify_content: JustifyContent::Center,
+                ..default()
+            },
+            ..default()
+        })
+        .id();
+    commands.entity(node).with_children(|parent| {
+        for (text, size) in [(headline.to_string(), 60.0), (detail, 40.0)] {
+            parent.spawn(TextBundle::from_section(
+                text,
+                TextStyle {
+                    font: blazma.clone(),
+                    font_size: size,
+                    color: Color::rgb(0.9, 0.9, 0.9),
+                },
+            ));
+        }
+    });
+    commands.entity(node).add_child(buttons);
+
+    spawn_menu_button(
+        &mut commands,
+        buttons,
+        "rematch",
+        blazma.clone(),
+        Some("rematch".to_string()),
+    );
+    spawn_menu_button(
+        &mut commands,
+        buttons,
+        "menu",
+        blazma,
+        Some("back_to_menu".to_string()),
+    );
+}
+
+pub fn match_over_update(
+    mut commands: Commands,
+    mut menu_button_pressed: EventReader<MenuButtonPressed>,
+    mut next_state: ResMut<NextState<AppState>>,
+    server_room: Option<Res<ServerRoom>>,
+    connection: Option<Res<ServerConnection>>,
+) {
+    for MenuButtonPressed(id) in menu_button_pressed.read() {
+        match id.as_str() {
+            // back to the server's room, to wait there for a second player
+            "rematch" => match &server_room {
+                // already on the way back
+                Some(_) if connection.is_some() => {}
+                Some(room) => match ServerConnection::connect(room.server, room.room.as_str()) {
+                    Ok(connection) => commands.insert_resource(connection),
+                    Err(e) => {
+                        warn!("couldn't rejoin room {}: {e}", room.room);
+                        next_state.set(AppState::MainMenu);
+                    }
+                },
+                None => next_state.set(AppState::InGame),
+            },
+            "back_to_menu" => {
+                // stop waiting in the server's room
+                commands.remove_resource::<ServerConnection>();
+                next_state.set(AppState::MainMenu);
+            }
+            _ => {}
+        }
+    }
+}
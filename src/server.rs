@@ -0,0 +1,672 @@
+//! A dedicated server that hosts 1v1 matches in named rooms. The server
+//! plays every match itself, with the same [`GamePlugin`](crate::GamePlugin)
+//! as local play. Clients only send their inputs and draw the states sent
+//! back; [`client`](crate::client) is the other end.
+
+use std::{
+    collections::HashMap,
+    f32::consts::{PI, TAU},
+    io,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    thread,
+    time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+use leafwing_input_manager::prelude::ActionState;
+
+use crate::{
+    calc,
+    game::{Score, Side},
+    headless,
+    match_setup::MatchConfig,
+    net::{take_over_paddles, NetInput},
+    player::{Player, RemoteInput},
+    rules::{MatchProgress, MatchResult},
+    Action,
+    Ball,
+    Paddle,
+    RotationQueue,
+};
+
+/// first bytes of every message, so stray traffic is ignored
+const MAGIC: [u8; 2] = *b"PS";
+/// longest room name, in bytes
+pub const MAX_ROOM_NAME: usize = 32;
+/// a client that hasn't sent anything for this long has left
+pub const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Something a client sends the server.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum ClientMessage {
+    /// asks to play in `room`, which is opened if nobody's in it yet
+    Join {
+        room: String,
+    },
+    /// the client's current input, numbered so late arrivals can be dropped
+    Input {
+        seq: u32,
+        input: NetInput,
+    },
+    Leave,
+}
+
+/// Where a paddle or the ball is at the end of a tick.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct Pose {
+    pub translation: Vec2,
+    /// radians anticlockwise
+    pub rotation: f32,
+}
+
+impl Pose {
+    pub fn from_transform(transform: &Transform) -> Self {
+        Self {
+            translation: transform.translation.truncate(),
+            rotation: transform.rotation.to_euler(EulerRot::XYZ).2,
+        }
+    }
+
+    pub fn apply_to(self, transform: &mut Transform) {
+        transform.translation = self.translation.extend(transform.translation.z);
+        transform.rotation = Quat::from_rotation_z(self.rotation);
+    }
+
+    /// Part of the way to `other`, turning the short way round.
+    pub fn lerp(self, other: Self, t: f32) -> Self {
+        let turn = (other.rotation - self.rotation + PI).rem_euclid(TAU) - PI;
+        Self {
+            translation: self.translation.lerp(other.translation, t),
+            rotation: self.rotation + turn * t,
+        }
+    }
+}
+
+/// Everything a client needs to draw one tick of its match.
+#[derive(Clone, PartialEq, Debug)]
+pub struct MatchState {
+    pub tick: u32,
+    pub score: Score,
+    pub sets: Score,
+    pub ball: Pose,
+    /// in player order
+    pub paddles: Vec<Pose>,
+}
+
+/// Something the server sends a client.
+#[derive(Clone, PartialEq, Debug)]
+pub enum ServerMessage {
+    /// the client is in, as `player`, and the match runs at `tick_rate`
+    /// ticks a second
+    Welcome {
+        player: u8,
+        tick_rate: u16,
+    },
+    /// the room already has two players
+    Full,
+    State(MatchState),
+    Over(MatchResult),
+    /// the other player left, so the match is off
+    Closed,
+}
+
+/// Reads a message front to back, giving up at the first missing byte.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    /// Checks the magic, and reads which kind of message it is.
+    fn open(bytes: &'a [u8]) -> Option<(u8, Self)> {
+        let (header, rest) = bytes.split_at_checked(3)?;
+        if header[..2] != MAGIC {
+            return None;
+        }
+        Some((header[2], Self(rest)))
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let (bytes, rest) = self.0.split_at_checked(len)?;
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.bytes(2)?.try_into().ok()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+
+    fn f32(&mut self) -> Option<f32> {
+        Some(f32::from_le_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+
+    fn score(&mut self) -> Option<Score> {
+        Some(Score {
+            left: self.u32()?,
+            right: self.u32()?,
+        })
+    }
+
+    fn pose(&mut self) -> Option<Pose> {
+        Some(Pose {
+            translation: Vec2::new(self.f32()?, self.f32()?),
+            rotation: self.f32()?,
+        })
+    }
+
+    /// `value`, as long as nothing's left over.
+    fn finish<T>(self, value: T) -> Option<T> {
+        self.0.is_empty().then_some(value)
+    }
+}
+
+fn header(kind: u8) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(64);
+    bytes.extend(MAGIC);
+    bytes.push(kind);
+    bytes
+}
+
+fn put_score(bytes: &mut Vec<u8>, score: Score) {
+    bytes.extend(score.left.to_le_bytes());
+    bytes.extend(score.right.to_le_bytes());
+}
+
+fn put_pose(bytes: &mut Vec<u8>, pose: Pose) {
+    bytes.extend(pose.translation.x.to_le_bytes());
+    bytes.extend(pose.translation.y.to_le_bytes());
+    bytes.extend(pose.rotation.to_le_bytes());
+}
+
+impl ClientMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Self::Join { room } => {
+                let mut bytes = header(0);
+                bytes.extend(room.as_bytes());
+                bytes
+            }
+            Self::Input { seq, input } => {
+                let mut bytes = header(1);
+                bytes.extend(seq.to_le_bytes());
+                bytes.extend(input.to_bytes());
+                bytes
+            }
+            Self::Leave => header(2),
+        }
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let (kind, mut reader) = Reader::open(bytes)?;
+        match kind {
+            0 => {
+                let room = std::str::from_utf8(reader.0).ok()?;
+                (room.len() <= MAX_ROOM_NAME).then(|| Self::Join {
+                    room: room.to_string(),
+                })
+            }
+            1 => {
+                let seq = reader.u32()?;
+                let input = NetInput::from_bytes(reader.bytes(NetInput::SIZE)?);
+                reader.finish(Self::Input { seq, input })
+            }
+            2 => reader.finish(Self::Leave),
+            _ => None,
+        }
+    }
+}
+
+impl ServerMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Self::Welcome { player, tick_rate } => {
+                let mut bytes = header(0);
+                bytes.push(*player);
+                bytes.extend(tick_rate.to_le_bytes());
+                bytes
+            }
+            Self::Full => header(1),
+            Self::State(state) => {
+                let mut bytes = header(2);
+                bytes.extend(state.tick.to_le_bytes());
+                put_score(&mut bytes, state.score);
+                put_score(&mut bytes, state.sets);
+                put_pose(&mut bytes, state.ball);
+                bytes.push(state.paddles.len() as u8);
+                for paddle in &state.paddles {
+                    put_pose(&mut bytes, *paddle);
+                }
+                bytes
+            }
+            Self::Over(result) => {
+                let mut bytes = header(3);
+                bytes.push(match result.winner {
+                    None => 0,
+                    Some(Side::Left) => 1,
+                    Some(Side::Right) => 2,
+                });
+                put_score(&mut bytes, result.sets);
+                put_score(&mut bytes, result.score);
+                bytes
+            }
+            Self::Closed => header(4),
+        }
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let (kind, mut reader) = Reader::open(bytes)?;
+        let message = match kind {
+            0 => Self::Welcome {
+                player: reader.u8()?,
+                tick_rate: reader.u16()?,
+            },
+            1 => Self::Full,
+            2 => {
+                let tick = reader.u32()?;
+                let score = reader.score()?;
+                let sets = reader.score()?;
+                let ball = reader.pose()?;
+                let paddles = (0..reader.u8()?)
+                    .map(|_| reader.pose())
+                    .collect::<Option<_>>()?;
+                Self::State(MatchState {
+                    tick,
+                    score,
+                    sets,
+                    ball,
+                    paddles,
+                })
+            }
+            3 => {
+                let winner = match reader.u8()? {
+                    0 => None,
+                    1 => Some(Side::Left),
+                    2 => Some(Side::Right),
+                    _ => return None,
+                };
+                Self::Over(MatchResult {
+                    winner,
+                    sets: reader.score()?,
+                    score: reader.score()?,
+                })
+            }
+            4 => Self::Closed,
+            _ => return None,
+        };
+        reader.finish(message)
+    }
+}
+
+/// A player in a room.
+struct Client {
+    addr: SocketAddr,
+    /// the newest input received
+    input: NetInput,
+    seq: u32,
+    /// rotate presses received since the last tick, clockwise or not, so a
+    /// tap between two ticks still counts
+    rotations: Vec<bool>,
+    last_heard: Instant,
+}
+
+/// A match, or one player waiting for an opponent.
+struct Room {
+    /// indexed by player
+    clients: Vec<Client>,
+    /// started once both players are in
+    app: Option<App>,
+    tick: u32,
+}
+
+impl Room {
+    fn start(&mut self, tick_rate: u16) {
+        // without arena files this is the default arena, which is the same
+        // box as the classic one the clients draw
+        let mut app = headless::headless_app(MatchConfig::default());
+        headless::set_tick_rate(&mut app, f64::from(tick_rate));
+        headless::start_match(&mut app);
+        take_over_paddles(&mut app.world, None);
+        self.app = Some(app);
+    }
+
+    /// Plays one tick with everyone's latest input, and says how it went.
+    fn tick(&mut self) -> Option<ServerMessage> {
+        let app = self.app.as_mut()?;
+        let mut query = app.world.query_filtered::<(
+            &Player,
+            &mut ActionState<Action>,
+            &mut RotationQueue,
+        ), With<RemoteInput>>();
+        for (player, mut action_state, mut queue) in query.iter_mut(&mut app.world) {
+            let Some(client) = self.clients.get_mut(player.0) else {
+                continue;
+            };
+            // rotate presses were picked up as they arrived, so none are new here
+            client
+                .input
+                .apply(&mut action_state, &mut queue, client.input);
+            for clockwise in client.rotations.drain(..) {
+                calc::queue_rotation(&mut queue, clockwise);
+            }
+        }
+        app.update();
+        self.tick += 1;
+
+        if let Some(result) = app.world.get_resource::<MatchResult>() {
+            return Some(ServerMessage::Over(*result));
+        }
+        Some(ServerMessage::State(match_state(&mut app.world, self.tick)))
+    }
+}
+
+fn match_state(world: &mut World, tick: u32) -> MatchState {
+    let ball = world
+        .query_filtered::<&Transform, With<Ball>>()
+        .iter(world)
+        .next()
+        .map(Pose::from_transform)
+        .unwrap_or_default();
+    let mut paddles: Vec<(usize, Pose)> = world
+        .query_filtered::<(&Player, &Transform), With<Paddle>>()
+        .iter(world)
+        .map(|(player, transform)| (player.0, Pose::from_transform(transform)))
+        .collect();
+    paddles.sort_by_key(|(player, _)| *player);
+    MatchState {
+        tick,
+        score: world.get_resource::<Score>().copied().unwrap_or_default(),
+        sets: world
+            .get_resource::<MatchProgress>()
+            .map(|progress| progress.sets)
+            .unwrap_or_default(),
+        ball,
+        paddles: paddles.into_iter().map(|(_, pose)| pose).collect(),
+    }
+}
+
+/// The room `addr` is in, and which player they are there.
+fn find_client(
+    rooms: &mut HashMap<String, Room>,
+    addr: SocketAddr,
+) -> Option<(&String, &mut Room, usize)> {
+    rooms.iter_mut().find_map(|(name, room)| {
+        let player = room.clients.iter().position(|client| client.addr == addr)?;
+        Some((name, room, player))
+    })
+}
+
+fn send(socket: &UdpSocket, to: SocketAddr, message: &ServerMessage) {
+    if let Err(e) = socket.send_to(&message.encode(), to) {
+        warn!("couldn't send to {to}: {e}");
+    }
+}
+
+/// Hosts any number of rooms on one socket, each playing its own match.
+pub struct Server {
+    socket: UdpSocket,
+    tick_rate: u16,
+    rooms: HashMap<String, Room>,
+}
+
+impl Server {
+    /// Listens on `addr`, playing every match at `tick_rate` ticks a second.
+    pub fn bind(addr: impl ToSocketAddrs, tick_rate: u16) -> io::Result<Self> {
+        if tick_rate == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the tick rate has to be at least 1",
+            ));
+        }
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            tick_rate,
+            rooms: HashMap::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub const fn tick_rate(&self) -> u16 {
+        self.tick_rate
+    }
+
+    /// How many rooms are open, whether their match has started or not.
+    pub fn rooms(&self) -> usize {
+        self.rooms.len()
+    }
+
+    /// Ticks every room `tick_rate` times a second, forever.
+    pub fn run(&mut self) -> ! {
+        let tick = Duration::from_secs_f64(1.0 / f64::from(self.tick_rate));
+        let mut next_tick = Instant::now();
+        loop {
+            thread::sleep(next_tick.saturating_duration_since(Instant::now()));
+            self.receive();
+            self.tick();
+            // after a stall, carry on from now rather than rushing to catch up
+            next_tick = (next_tick + tick).max(Instant::now());
+        }
+    }
+
+    /// Handles everything clients have sent since the last call.
+    pub fn receive(&mut self) {
+        let mut buffer = [0; 1024];
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                // a client's port has closed
+                Err(e) if e.kind() == io::ErrorKind::ConnectionReset => continue,
+                Err(e) => {
+                    warn!("couldn't receive: {e}");
+                    break;
+                }
+            };
+            if let Some(message) = ClientMessage::decode(&buffer[..len]) {
+                self.handle(from, message);
+            }
+        }
+    }
+
+    fn handle(&mut self, from: SocketAddr, message: ClientMessage) {
+        if message == ClientMessage::Leave {
+            self.leave(from);
+            return;
+        }
+        let tick_rate = self.tick_rate;
+        if let Some((_, room, player)) = find_client(&mut self.rooms, from) {
+            let client = &mut room.clients[player];
+            client.last_heard = Instant::now();
+            match message {
+                // the welcome must have been lost
+                ClientMessage::Join { .. } => send(
+                    &self.socket,
+                    from,
+                    &ServerMessage::Welcome {
+                        player: player as u8,
+                        tick_rate,
+                    },
+                ),
+                ClientMessage::Input { seq, input } if seq > client.seq => {
+                    for (action, clockwise) in [
+                        (Action::RotateAntiClockwise, false),
+                        (Action::RotateClockwise, true),
+                    ] {
+                        if input.pressed(action) && !client.input.pressed(action) {
+                            client.rotations.push(clockwise);
+                        }
+                    }
+                    client.seq = seq;
+                    client.input = input;
+                }
+                ClientMessage::Input { .. } | ClientMessage::Leave => {}
+            }
+            return;
+        }
+
+        let ClientMessage::Join { room: name } = message else {
+            return;
+        };
+        let room = self.rooms.entry(name.clone()).or_insert_with(|| Room {
+            clients: Vec::new(),
+            app: None,
+            tick: 0,
+        });
+        if room.clients.len() >= 2 {
+            send(&self.socket, from, &ServerMessage::Full);
+            return;
+        }
+        let player = room.clients.len();
+        room.clients.push(Client {
+            addr: from,
+            input: NetInput::default(),
+            seq: 0,
+            rotations: Vec::new(),
+            last_heard: Instant::now(),
+        });
+        info!("{from} joined room {name} as player {}", player + 1);
+        send(
+            &self.socket,
+            from,
+            &ServerMessage::Welcome {
+                player: player as u8,
+                tick_rate,
+            },
+        );
+        if room.clients.len() == 2 {
+            info!("room {name} is starting");
+            room.start(tick_rate);
+        }
+    }
+
+    /// Closes the room `addr` is in, telling anyone else there.
+    fn leave(&mut self, addr: SocketAddr) {
+        let Some((name, _, _)) = find_client(&mut self.rooms, addr) else {
+            return;
+        };
+        let name = name.clone();
+        let Some(room) = self.rooms.remove(&name) else {
+            return;
+        };
+        info!("{addr} left room {name}");
+        for client in room.clients.iter().filter(|client| client.addr != addr) {
+            send(&self.socket, client.addr, &ServerMessage::Closed);
+        }
+    }
+
+    /// Drops clients that have gone quiet, then plays a tick in every room
+    /// with a match on and sends everyone the result.
+    pub fn tick(&mut self) {
+        let quiet: Vec<SocketAddr> = self
+            .rooms
+            .values()
+            .flat_map(|room| &room.clients)
+            .filter(|client| client.last_heard.elapsed() > CLIENT_TIMEOUT)
+            .map(|client| client.addr)
+            .collect();
+        for addr in quiet {
+            self.leave(addr);
+        }
+
+        let mut over = Vec::new();
+        for (name, room) in &mut self.rooms {
+            let Some(message) = room.tick() else {
+                continue;
+            };
+            if matches!(message, ServerMessage::Over(_)) {
+                info!("room {name} has finished");
+                over.push(name.clone());
+            }
+            for client in &room.clients {
+                send(&self.socket, client.addr, &message);
+            }
+        }
+        for name in over {
+            self.rooms.remove(&name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_round_trip() {
+        let mut source = ActionState::<Action>::default();
+        source.press(Action::RotateClockwise);
+        let client_messages = [
+            ClientMessage::Join {
+                room: "lobby".to_string(),
+            },
+            ClientMessage::Input {
+                seq: 9,
+                input: NetInput::from_action_state(&source),
+            },
+            ClientMessage::Leave,
+        ];
+        for message in client_messages {
+            assert_eq!(ClientMessage::decode(&message.encode()), Some(message));
+        }
+
+        let pose = Pose {
+            translation: Vec2::new(-300.5, 12.25),
+            rotation: 1.5,
+        };
+        let server_messages = [
+            ServerMessage::Welcome {
+                player: 1,
+                tick_rate: 30,
+            },
+            ServerMessage::Full,
+            ServerMessage::State(MatchState {
+                tick: 400,
+                score: Score { left: 3, right: 1 },
+                sets: Score { left: 0, right: 1 },
+                ball: pose,
+                paddles: vec![pose, Pose::default()],
+            }),
+            ServerMessage::Over(MatchResult {
+                winner: Some(Side::Right),
+                sets: Score { left: 1, right: 2 },
+                score: Score { left: 4, right: 6 },
+            }),
+            ServerMessage::Closed,
+        ];
+        for message in server_messages {
+            assert_eq!(ServerMessage::decode(&message.encode()), Some(message));
+        }
+
+        assert_eq!(ServerMessage::decode(b"PP\x01"), None);
+        assert_eq!(ServerMessage::decode(b"PS\x01extra"), None);
+        let long = ClientMessage::Join {
+            room: "x".repeat(MAX_ROOM_NAME + 1),
+        };
+        assert_eq!(ClientMessage::decode(&long.encode()), None);
+    }
+
+    #[test]
+    fn poses_turn_the_short_way() {
+        let a = Pose {
+            translation: Vec2::ZERO,
+            rotation: 3.0,
+        };
+        let b = Pose {
+            translation: Vec2::new(10.0, 0.0),
+            rotation: -3.0,
+        };
+        let half = a.lerp(b, 0.5);
+        assert_eq!(half.translation, Vec2::new(5.0, 0.0));
+        // through PI, not through 0
+        assert!((half.rotation.abs() - PI).abs() < 0.01, "{}", half.rotation);
+    }
+}